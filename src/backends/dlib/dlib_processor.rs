@@ -2,52 +2,90 @@ use crate::{
     error::FacialProcessingError,
     face_processor_trait::FaceProcessorTrait,
     utils::{
+        detection::Detection,
         eyes::Eye,
         face::FaceLandmark,
-        misc::{BoundingBox, EulerAngles, Point2D},
+        frame::Frame,
+        identity::FaceEmbedding,
+        layout::LandmarkLayout,
+        misc::{BoundingBox, Point2D, SupportedProcesses},
+        model_config::DLibDetector,
+        parallel::parallel_map,
     },
 };
 use dlib_face_recognition::{
-    FaceDetector, FaceDetectorTrait, ImageMatrix, LandmarkPredictor, LandmarkPredictorTrait,
-    Rectangle,
+    FaceDetector, FaceDetectorCnn, FaceDetectorTrait, FaceEncoderNetwork, FaceEncoderTrait,
+    ImageMatrix, LandmarkPredictor, LandmarkPredictorTrait, Rectangle,
 };
 use image::{ImageBuffer, Rgb};
 use std::path::Path;
 
 pub struct DLibProcessor {
-    face_detector: FaceDetector,
+    face_detector: Box<dyn FaceDetectorTrait>,
     landmark_detector: LandmarkPredictor,
+    landmark_layout: LandmarkLayout,
+    face_encoder: Option<FaceEncoderNetwork>,
+    // how many faces are landmarked at once. Detection stays on one thread: the HOG scanner and the
+    // CNN keep scratch buffers between calls. Landmarking is a const call on the shape predictor
+    // that only reads the trained model, so one predictor is shared by all the threads.
+    threads: usize,
 }
 
 impl DLibProcessor {
-    pub fn new<P: AsRef<Path>>(landmark_detector: P) -> Result<Self, FacialProcessingError> {
+    /// `landmark_layout` is the markup of the `landmark_detector` model, `Ibug68` or `FivePoint` for
+    /// the models dlib ships. `face_encoder` (`dlib_face_recognition_resnet_model_v1.dat`) is only
+    /// needed for embeddings.
+    pub fn new<P: AsRef<Path>>(
+        face_detector: DLibDetector,
+        landmark_detector: P,
+        landmark_layout: LandmarkLayout,
+        face_encoder: Option<P>,
+    ) -> Result<Self, FacialProcessingError> {
+        let face_detector: Box<dyn FaceDetectorTrait> = match face_detector {
+            DLibDetector::Hog => Box::new(FaceDetector::new()),
+            DLibDetector::Cnn { model_path } => match FaceDetectorCnn::new(model_path) {
+                Ok(cnn) => Box::new(cnn),
+                Err(why) => return Err(FacialProcessingError::InitializeError(why)),
+            },
+        };
         let landmark = match LandmarkPredictor::new(landmark_detector) {
             Ok(land) => land,
             Err(why) => return Err(FacialProcessingError::InitializeError(why)),
         };
+        let face_encoder = match face_encoder {
+            Some(path) => match FaceEncoderNetwork::new(path) {
+                Ok(encoder) => Some(encoder),
+                Err(why) => return Err(FacialProcessingError::InitializeError(why)),
+            },
+            None => None,
+        };
         Ok(DLibProcessor {
-            face_detector: FaceDetector::new(),
+            face_detector,
             landmark_detector: landmark,
+            landmark_layout,
+            face_encoder,
+            threads: 1,
         })
     }
 
-    pub fn detect_faces(&self, data: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<BoundingBox> {
+    /// dlib does not report scores, so every detection scores 1.
+    pub fn detect_faces(&self, data: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<Detection> {
         self.detect_faces_imagematrix(&ImageMatrix::from_image(data))
     }
 
-    pub fn detect_faces_imagematrix(&self, data: &ImageMatrix) -> Vec<BoundingBox> {
-        let mut boxes: Vec<BoundingBox> = vec![];
+    pub fn detect_faces_imagematrix(&self, data: &ImageMatrix) -> Vec<Detection> {
+        let mut faces: Vec<Detection> = vec![];
         for rect in self.face_detector.face_locations(data).iter() {
-            boxes.push(BoundingBox::from(*rect))
+            faces.push(Detection::from(BoundingBox::from(*rect)))
         }
-        boxes
+        faces
     }
 
     pub fn landmark_faces(
         &self,
         data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
         bbox: BoundingBox,
-    ) -> FaceLandmark {
+    ) -> Result<FaceLandmark, FacialProcessingError> {
         self.landmark_faces_imagematrix(&ImageMatrix::from_image(data), bbox)
     }
 
@@ -55,33 +93,103 @@ impl DLibProcessor {
         &self,
         data: &ImageMatrix,
         bbox: BoundingBox,
-    ) -> FaceLandmark {
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        let landmark = self.landmark_detector.face_landmarks(data, &bbox.into());
+        FaceLandmark::from_dlib(bbox, landmark.to_vec(), self.landmark_layout.clone())
+    }
+
+    /// Compute the 128 value dlib face descriptor of the face in `bbox`.
+    pub fn face_embedding_imagematrix(
+        &self,
+        data: &ImageMatrix,
+        bbox: BoundingBox,
+    ) -> Result<FaceEmbedding, FacialProcessingError> {
+        let encoder = match &self.face_encoder {
+            Some(e) => e,
+            None => {
+                return Err(FacialProcessingError::UnsupportedProcess(
+                    SupportedProcesses::Recognition,
+                ))
+            }
+        };
+        // the encoder aligns the face with dlib's own landmark type
         let landmark = self.landmark_detector.face_landmarks(data, &bbox.into());
-        FaceLandmark::from_dlib(bbox, landmark.to_vec())
+        let encodings = encoder.get_face_encodings(data, &[landmark], 0);
+        match encodings.first() {
+            Some(encoding) => {
+                let values: &[f64] = encoding.as_ref();
+                Ok(FaceEmbedding::new(values.to_vec()))
+            }
+            None => Err(FacialProcessingError::InternalError(
+                "Face encoder returned no encoding".to_string(),
+            )),
+        }
     }
 }
 
 impl FaceProcessorTrait for DLibProcessor {
-    fn init(&self, cpu: i16, confidence: f32) -> Result<(), FacialProcessingError> {
-        // nothing to-do here.
+    /// dlib's detectors do not report scores, so `confidence` has no effect. `threads` only spreads
+    /// landmarking over the faces of a frame, detection runs on one thread.
+    fn init(
+        &mut self,
+        threads: Option<i16>,
+        _confidence: Option<f32>,
+    ) -> Result<(), FacialProcessingError> {
+        if let Some(threads) = threads {
+            self.threads = threads.max(1) as usize;
+        }
         Ok(())
     }
 
-    fn get_face_detections(&self, data: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<BoundingBox> {
-        self.detect_faces(data)
+    fn get_face_detections(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        Ok(self.detect_faces(frame.image()))
     }
 
     fn get_face_landmark(
-        &self,
-        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        &mut self,
+        frame: &Frame,
         bbox: BoundingBox,
-    ) -> FaceLandmark {
-        self.landmark_faces(data, bbox)
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        self.landmark_faces(frame.image(), bbox)
     }
 
-    fn get_pnp_forward(&self,
-        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-        landmark: FaceLandmark) -> EulerAngles {
-            todo!()
+    fn get_face_landmarks(
+        &mut self,
+        frame: &Frame,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        // convert the frame once for all the faces
+        let matrix = ImageMatrix::from_image(frame.image());
+        // shape_predictor::operator() is const and the matrix is only read, see `threads`
+        let (predictor, layout) = (&self.landmark_detector, &self.landmark_layout);
+        parallel_map(bboxes, self.threads, |bbox| {
+            let landmark = predictor.face_landmarks(&matrix, &(*bbox).into());
+            FaceLandmark::from_dlib(*bbox, landmark.to_vec(), layout.clone())
+        })
+        .into_iter()
+        .collect()
+    }
+
+    fn get_face_embedding(
+        &mut self,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<FaceEmbedding, FacialProcessingError> {
+        self.face_embedding_imagematrix(&ImageMatrix::from_image(frame.image()), bbox)
+    }
+
+    fn supported_processes(&self) -> Vec<SupportedProcesses> {
+        let mut processes = vec![SupportedProcesses::Detection, SupportedProcesses::Alignment];
+        if self.face_encoder.is_some() {
+            processes.push(SupportedProcesses::Recognition);
         }
+        processes
+    }
+
+    fn landmark_layout(&self) -> LandmarkLayout {
+        self.landmark_layout.clone()
+    }
 }
@@ -1,4 +1,8 @@
 #[cfg(feature = "dlib")]
 pub mod dlib;
+#[cfg(feature = "opencv_backend")]
+pub mod opencv;
 #[cfg(feature = "openvtuber")]
 pub mod openvtuber;
+#[cfg(feature = "onnx")]
+pub mod onnx;
@@ -0,0 +1,302 @@
+use crate::{
+    error::FacialProcessingError,
+    face_processor_trait::FaceProcessorTrait,
+    utils::{
+        detection::{non_max_suppression, Detection},
+        face::FaceLandmark,
+        frame::Frame,
+        layout::LandmarkLayout,
+        misc::{BoundingBox, Point2D, SupportedProcesses},
+        model_config::{DetectorDecoding, DetectorModelConfig, LandmarkModelConfig, Preprocessing},
+        parallel::parallel_map,
+    },
+};
+use image::{imageops::resize, imageops::FilterType, ImageBuffer, Rgb};
+use tract_onnx::prelude::*;
+
+type OnnxModel = TypedRunnableModel<TypedModel>;
+
+pub struct OnnxProcessor {
+    detector: OnnxModel,
+    detector_config: DetectorModelConfig,
+    landmark: OnnxModel,
+    landmark_config: LandmarkModelConfig,
+    // tract runs a model on the calling thread, so this is how many faces are landmarked at once
+    threads: usize,
+}
+
+impl OnnxProcessor {
+    pub fn new(
+        detector_config: DetectorModelConfig,
+        landmark_config: LandmarkModelConfig,
+    ) -> Result<Self, FacialProcessingError> {
+        let detector = match load_model(
+            &detector_config.model_path,
+            detector_config.input_width,
+            detector_config.input_height,
+            &detector_config.preprocessing,
+        ) {
+            Ok(m) => m,
+            Err(why) => return Err(why),
+        };
+        let landmark = match load_model(
+            &landmark_config.model_path,
+            landmark_config.input_width,
+            landmark_config.input_height,
+            &landmark_config.preprocessing,
+        ) {
+            Ok(m) => m,
+            Err(why) => return Err(why),
+        };
+        Ok(OnnxProcessor {
+            detector,
+            detector_config,
+            landmark,
+            landmark_config,
+            threads: 1,
+        })
+    }
+
+    pub fn detect_faces(
+        &self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        let config = &self.detector_config;
+        let input = resize(
+            data,
+            config.input_width,
+            config.input_height,
+            FilterType::Triangle,
+        );
+        let outputs = match run_model(
+            &self.detector,
+            &config.preprocessing,
+            &input,
+            config.input_width,
+            config.input_height,
+        ) {
+            Ok(o) => o,
+            Err(why) => return Err(why),
+        };
+
+        // the outputs are relative to the input, scale them to the image
+        let (width, height) = (data.width() as f32, data.height() as f32);
+        let to_box = |corners: &[f32]| BoundingBox {
+            x_minumum: (corners[0] * width).max(0.0) as i32,
+            x_maximum: (corners[2] * width).min(width) as i32,
+            y_minumum: (corners[1] * height).max(0.0) as i32,
+            y_maximum: (corners[3] * height).min(height) as i32,
+        };
+        let mut candidates: Vec<Detection> = vec![];
+        match config.decoding {
+            DetectorDecoding::UltraFace => {
+                let (scores, boxes) = match (outputs.first(), outputs.get(1)) {
+                    (Some(scores), Some(boxes)) => (scores, boxes),
+                    _ => {
+                        return Err(FacialProcessingError::InternalError(format!(
+                            "UltraFace decoding needs 2 outputs, the model has {}",
+                            outputs.len()
+                        )))
+                    }
+                };
+                let scores = match scores.as_slice::<f32>() {
+                    Ok(s) => s,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+                let boxes = match boxes.as_slice::<f32>() {
+                    Ok(b) => b,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+                for (score, bbox) in scores.chunks_exact(2).zip(boxes.chunks_exact(4)) {
+                    if score[1] >= config.score_threshold {
+                        candidates.push(Detection::new(to_box(bbox), score[1]));
+                    }
+                }
+            }
+            DetectorDecoding::Rows {
+                normalized,
+                keypoints,
+            } => {
+                let rows = match outputs.first() {
+                    Some(r) => r,
+                    None => {
+                        return Err(FacialProcessingError::InternalError(
+                            "Detection model has no outputs".to_string(),
+                        ))
+                    }
+                };
+                let rows = match rows.as_slice::<f32>() {
+                    Ok(r) => r,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+                let (scale_x, scale_y) = match normalized {
+                    true => (1.0, 1.0),
+                    false => (config.input_width as f32, config.input_height as f32),
+                };
+                let row_len = if keypoints { 15 } else { 5 };
+                for row in rows.chunks_exact(row_len) {
+                    if row[4] < config.score_threshold {
+                        continue;
+                    }
+                    let corners = [
+                        row[0] / scale_x,
+                        row[1] / scale_y,
+                        row[2] / scale_x,
+                        row[3] / scale_y,
+                    ];
+                    let mut detection = Detection::new(to_box(&corners), row[4]);
+                    if keypoints {
+                        let mut points = [Point2D::default(); 5];
+                        for (point, xy) in points.iter_mut().zip(row[5..].chunks_exact(2)) {
+                            *point = Point2D::new(
+                                (xy[0] / scale_x * width) as f64,
+                                (xy[1] / scale_y * height) as f64,
+                            );
+                        }
+                        detection = detection.with_keypoints(points);
+                    }
+                    candidates.push(detection);
+                }
+            }
+        }
+
+        Ok(non_max_suppression(candidates, config.nms_threshold))
+    }
+
+    pub fn landmark_face(
+        &self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        let config = &self.landmark_config;
+        let (input, crop_box) = config.crop(data, bbox);
+        let outputs = match run_model(
+            &self.landmark,
+            &config.preprocessing,
+            &input,
+            config.input_width,
+            config.input_height,
+        ) {
+            Ok(o) => o,
+            Err(why) => return Err(why),
+        };
+        let raw = match outputs.first() {
+            Some(r) => r,
+            None => {
+                return Err(FacialProcessingError::InternalError(
+                    "Landmark model has no outputs".to_string(),
+                ))
+            }
+        };
+        let raw = match raw.as_slice::<f32>() {
+            Ok(r) => r,
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        };
+
+        let points = raw
+            .chunks_exact(2)
+            .take(config.layout.point_count())
+            .map(|pt| config.decode_point(pt[0], pt[1], crop_box))
+            .collect();
+        FaceLandmark::new(bbox, points, config.layout.clone())
+    }
+}
+
+impl FaceProcessorTrait for OnnxProcessor {
+    fn init(
+        &mut self,
+        threads: Option<i16>,
+        confidence: Option<f32>,
+    ) -> Result<(), FacialProcessingError> {
+        if let Some(threads) = threads {
+            self.threads = threads.max(1) as usize;
+        }
+        if let Some(confidence) = confidence {
+            self.detector_config.score_threshold = confidence;
+        }
+        Ok(())
+    }
+
+    fn get_face_detections(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        self.detect_faces(frame.image())
+    }
+
+    fn get_face_landmark(
+        &mut self,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        self.landmark_face(frame.image(), bbox)
+    }
+
+    fn get_face_landmarks(
+        &mut self,
+        frame: &Frame,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        let this = &*self;
+        parallel_map(bboxes, self.threads, |bbox| {
+            this.landmark_face(frame.image(), *bbox)
+        })
+        .into_iter()
+        .collect()
+    }
+
+    fn supported_processes(&self) -> Vec<SupportedProcesses> {
+        vec![SupportedProcesses::Detection, SupportedProcesses::Alignment]
+    }
+
+    fn landmark_layout(&self) -> LandmarkLayout {
+        self.landmark_config.layout.clone()
+    }
+}
+
+fn load_model(
+    path: &str,
+    width: u32,
+    height: u32,
+    preprocessing: &Preprocessing,
+) -> Result<OnnxModel, FacialProcessingError> {
+    let shape = preprocessing.input_shape(width, height);
+    let model = match tract_onnx::onnx().model_for_path(path) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InitializeError(why.to_string())),
+    };
+    let model = match model.with_input_fact(
+        0,
+        InferenceFact::dt_shape(
+            f32::datum_type(),
+            tvec!(shape[0], shape[1], shape[2], shape[3]),
+        ),
+    ) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InitializeError(why.to_string())),
+    };
+    match model.into_optimized().and_then(|m| m.into_runnable()) {
+        Ok(m) => Ok(m),
+        Err(why) => Err(FacialProcessingError::InitializeError(why.to_string())),
+    }
+}
+
+fn run_model(
+    model: &OnnxModel,
+    preprocessing: &Preprocessing,
+    input: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    width: u32,
+    height: u32,
+) -> Result<TVec<TValue>, FacialProcessingError> {
+    let tensor = match Tensor::from_shape(
+        &preprocessing.input_shape(width, height),
+        &preprocessing.tensor_data(input),
+    ) {
+        Ok(t) => t,
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    };
+    match model.run(tvec!(tensor.into())) {
+        Ok(outputs) => Ok(outputs),
+        Err(why) => Err(FacialProcessingError::InternalError(why.to_string())),
+    }
+}
@@ -0,0 +1,2 @@
+#[cfg(feature = "opencv_backend")]
+pub mod opencv_processor;
@@ -0,0 +1,433 @@
+use crate::{
+    error::FacialProcessingError,
+    face_processor_trait::FaceProcessorTrait,
+    mat_init,
+    utils::{
+        calibration::luma_to_mat,
+        detection::Detection,
+        face::FaceLandmark,
+        frame::{Frame, PixelFormat},
+        layout::LandmarkLayout,
+        misc::{bboxes_to_rects, BoundingBox, Point2D, SupportedProcesses},
+        model_config::OpenCVDetector,
+    },
+};
+use image::{ImageBuffer, Rgb};
+use opencv::{
+    core::{set_num_threads, Mat, Point2f, Ptr, Rect, Scalar, Size, Vector, CV_32F, CV_8UC3},
+    dnn::{blob_from_image, read_net_from_caffe, Net, NetTrait},
+    face::{create_facemark_lbf, Facemark},
+    imgproc::{cvt_color, equalize_hist, COLOR_BGR2GRAY, COLOR_RGB2BGR},
+    objdetect::{CascadeClassifier, CascadeClassifierTrait},
+    prelude::*,
+};
+
+const SSD_INPUT_SIZE: i32 = 300;
+// The SSD's output is `[1, 1, N, 7]`, with rows of `image id, class, score, x1, y1, x2, y2`.
+const SSD_ROW_LEN: usize = 7;
+
+enum Detector {
+    Cascade {
+        classifier: CascadeClassifier,
+        scale_factor: f64,
+        min_neighbors: i32,
+    },
+    Res10Ssd {
+        net: Net,
+    },
+}
+
+pub struct OpenCVProcessor {
+    detector: Detector,
+    facemark: Ptr<dyn Facemark>,
+    score_threshold: f32,
+}
+
+impl OpenCVProcessor {
+    /// `facemark_lbf_path` is an LBF model for the 68 point markup, such as `lbfmodel.yaml`.
+    pub fn new(
+        detector: OpenCVDetector,
+        facemark_lbf_path: String,
+    ) -> Result<Self, FacialProcessingError> {
+        let (detector, score_threshold) = match detector {
+            OpenCVDetector::Cascade {
+                cascade_path,
+                scale_factor,
+                min_neighbors,
+            } => {
+                let classifier = match CascadeClassifier::new(&cascade_path) {
+                    Ok(c) => c,
+                    Err(why) => {
+                        return Err(FacialProcessingError::InitializeError(why.to_string()))
+                    }
+                };
+                // an empty classifier means the file could not be parsed
+                match classifier.empty() {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        return Err(FacialProcessingError::InitializeError(format!(
+                            "Could not load cascade {}",
+                            cascade_path
+                        )))
+                    }
+                    Err(why) => {
+                        return Err(FacialProcessingError::InitializeError(why.to_string()))
+                    }
+                }
+                (
+                    Detector::Cascade {
+                        classifier,
+                        scale_factor,
+                        min_neighbors,
+                    },
+                    0.0,
+                )
+            }
+            OpenCVDetector::Res10Ssd {
+                prototxt_path,
+                model_path,
+                score_threshold,
+            } => {
+                let net = match read_net_from_caffe(&prototxt_path, &model_path) {
+                    Ok(n) => n,
+                    Err(why) => {
+                        return Err(FacialProcessingError::InitializeError(why.to_string()))
+                    }
+                };
+                (Detector::Res10Ssd { net }, score_threshold)
+            }
+        };
+
+        let mut facemark = match create_facemark_lbf() {
+            Ok(f) => f,
+            Err(why) => return Err(FacialProcessingError::InitializeError(why.to_string())),
+        };
+        if let Err(why) = facemark.load_model(&facemark_lbf_path) {
+            return Err(FacialProcessingError::InitializeError(why.to_string()));
+        }
+
+        Ok(OpenCVProcessor {
+            detector,
+            facemark,
+            score_threshold,
+        })
+    }
+
+    /// Cascade detections all score 1.
+    pub fn detect_faces(
+        &mut self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        match rgb_to_bgr_mat(data) {
+            Ok(image) => self.detect_faces_mat(&image),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// `detect_faces` for an 8 bit BGR `Mat`.
+    pub fn detect_faces_mat(
+        &mut self,
+        image: &Mat,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        match &mut self.detector {
+            Detector::Cascade {
+                classifier,
+                scale_factor,
+                min_neighbors,
+            } => {
+                let mut gray = mat_init!();
+                if let Err(why) = cvt_color(image, &mut gray, COLOR_BGR2GRAY, 0) {
+                    return Err(FacialProcessingError::InternalError(why.to_string()));
+                }
+                run_cascade(classifier, *scale_factor, *min_neighbors, &gray)
+            }
+            Detector::Res10Ssd { net } => {
+                let blob = match blob_from_image(
+                    image,
+                    1.0,
+                    Size::new(SSD_INPUT_SIZE, SSD_INPUT_SIZE),
+                    Scalar::new(104.0, 177.0, 123.0, 0.0),
+                    false,
+                    false,
+                    CV_32F,
+                ) {
+                    Ok(b) => b,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+                if let Err(why) = net.set_input(&blob, "", 1.0, Scalar::default()) {
+                    return Err(FacialProcessingError::InternalError(why.to_string()));
+                }
+                let output = match net.forward_single("") {
+                    Ok(o) => o,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+                let rows = match output.data_typed::<f32>() {
+                    Ok(r) => r,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+
+                let threshold = self.score_threshold;
+                let (width, height) = (image.cols() as f32, image.rows() as f32);
+                Ok(rows
+                    .chunks_exact(SSD_ROW_LEN)
+                    .filter(|row| row[2] >= threshold)
+                    .map(|row| {
+                        Detection::new(
+                            BoundingBox {
+                                x_minumum: (row[3].max(0.0) * width) as i32,
+                                x_maximum: (row[5].min(1.0) * width) as i32,
+                                y_minumum: (row[4].max(0.0) * height) as i32,
+                                y_maximum: (row[6].min(1.0) * height) as i32,
+                            },
+                            row[2],
+                        )
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    pub fn landmark_face(
+        &mut self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        match rgb_to_bgr_mat(data) {
+            Ok(image) => self.landmark_face_mat(&image, bbox),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// `landmark_face` for an 8 bit BGR `Mat`.
+    pub fn landmark_face_mat(
+        &mut self,
+        image: &Mat,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        let mut landmarks = match self.landmark_faces_mat(image, &[bbox]) {
+            Ok(l) => l,
+            Err(why) => return Err(why),
+        };
+        match landmarks.pop() {
+            Some(landmark) => Ok(landmark),
+            None => Err(FacialProcessingError::InternalError(
+                "Facemark returned no landmarks".to_string(),
+            )),
+        }
+    }
+
+    /// Fit every face in one Facemark call.
+    pub fn landmark_faces(
+        &mut self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        if bboxes.is_empty() {
+            return Ok(vec![]);
+        }
+        match rgb_to_bgr_mat(data) {
+            Ok(image) => self.landmark_faces_mat(&image, bboxes),
+            Err(why) => Err(why),
+        }
+    }
+
+    /// `landmark_faces` for an 8 bit BGR `Mat`.
+    pub fn landmark_faces_mat(
+        &mut self,
+        image: &Mat,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        if bboxes.is_empty() {
+            return Ok(vec![]);
+        }
+        let faces = bboxes_to_rects(bboxes);
+
+        let mut landmarks: Vector<Vector<Point2f>> = Vector::new();
+        match self.facemark.fit(image, &faces, &mut landmarks) {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(FacialProcessingError::InternalError(
+                    "Facemark could not fit the faces".to_string(),
+                ))
+            }
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        }
+        if landmarks.len() != bboxes.len() {
+            return Err(FacialProcessingError::InternalError(format!(
+                "Facemark fit {} of {} faces",
+                landmarks.len(),
+                bboxes.len()
+            )));
+        }
+
+        bboxes
+            .iter()
+            .zip(landmarks.iter())
+            .map(|(bbox, points)| {
+                FaceLandmark::new(
+                    *bbox,
+                    points
+                        .iter()
+                        .map(|pt| Point2D::new(pt.x as f64, pt.y as f64))
+                        .collect(),
+                    LandmarkLayout::Ibug68,
+                )
+            })
+            .collect()
+    }
+}
+
+impl FaceProcessorTrait for OpenCVProcessor {
+    /// OpenCV's thread count is global, so this also affects other OpenCV users in the process.
+    /// Cascades do not score their detections, so `confidence` only applies to the SSD.
+    fn init(
+        &mut self,
+        threads: Option<i16>,
+        confidence: Option<f32>,
+    ) -> Result<(), FacialProcessingError> {
+        if let Some(threads) = threads {
+            if let Err(why) = set_num_threads(threads as i32) {
+                return Err(FacialProcessingError::InitializeError(why.to_string()));
+            }
+        }
+        if let Some(confidence) = confidence {
+            self.score_threshold = confidence;
+        }
+        Ok(())
+    }
+
+    fn get_face_detections(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        // cascades only need the luma, which YUV and gray frames have without an RGB conversion
+        if let Detector::Cascade {
+            classifier,
+            scale_factor,
+            min_neighbors,
+        } = &mut self.detector
+        {
+            let gray = match luma_to_mat(frame.luma()) {
+                Ok(m) => m,
+                Err(why) => return Err(why),
+            };
+            return run_cascade(classifier, *scale_factor, *min_neighbors, &gray);
+        }
+        match frame_to_bgr_mat(frame) {
+            Ok(image) => self.detect_faces_mat(&image),
+            Err(why) => Err(why),
+        }
+    }
+
+    fn get_face_landmark(
+        &mut self,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        match frame_to_bgr_mat(frame) {
+            Ok(image) => self.landmark_face_mat(&image, bbox),
+            Err(why) => Err(why),
+        }
+    }
+
+    fn get_face_landmarks(
+        &mut self,
+        frame: &Frame,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        if bboxes.is_empty() {
+            return Ok(vec![]);
+        }
+        match frame_to_bgr_mat(frame) {
+            Ok(image) => self.landmark_faces_mat(&image, bboxes),
+            Err(why) => Err(why),
+        }
+    }
+
+    fn supported_processes(&self) -> Vec<SupportedProcesses> {
+        vec![SupportedProcesses::Detection, SupportedProcesses::Alignment]
+    }
+
+    fn landmark_layout(&self) -> LandmarkLayout {
+        LandmarkLayout::Ibug68
+    }
+}
+
+// BGR frames, such as ones from `Frame::from_mat`, are copied as they are instead of being converted
+// to RGB and back.
+fn frame_to_bgr_mat(frame: &Frame) -> Result<Mat, FacialProcessingError> {
+    let (data, stride) = match frame.packed() {
+        Some((data, PixelFormat::Bgr, stride)) => (data, stride),
+        _ => return rgb_to_bgr_mat(frame.image()),
+    };
+    let mut bgr = match Mat::new_rows_cols_with_default(
+        frame.height() as i32,
+        frame.width() as i32,
+        CV_8UC3,
+        Scalar::all(0.0),
+    ) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    };
+    let row_len = frame.width() as usize * 3;
+    match bgr.data_bytes_mut() {
+        Ok(bytes) => {
+            // the frame checked that every row fits in `data`
+            for (y, row) in bytes.chunks_exact_mut(row_len.max(1)).enumerate() {
+                row.copy_from_slice(&data[y * stride..y * stride + row_len]);
+            }
+        }
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    }
+    Ok(bgr)
+}
+
+fn rgb_to_bgr_mat(image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Result<Mat, FacialProcessingError> {
+    let mut rgb = match Mat::new_rows_cols_with_default(
+        image.height() as i32,
+        image.width() as i32,
+        CV_8UC3,
+        Scalar::all(0.0),
+    ) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    };
+    match rgb.data_bytes_mut() {
+        Ok(bytes) => bytes.copy_from_slice(image.as_raw()),
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    }
+    let mut bgr = mat_init!();
+    if let Err(why) = cvt_color(&rgb, &mut bgr, COLOR_RGB2BGR, 0) {
+        return Err(FacialProcessingError::InternalError(why.to_string()));
+    }
+    Ok(bgr)
+}
+
+// Cascade detections all score 1.
+fn run_cascade(
+    classifier: &mut CascadeClassifier,
+    scale_factor: f64,
+    min_neighbors: i32,
+    gray: &Mat,
+) -> Result<Vec<Detection>, FacialProcessingError> {
+    let mut equalized = mat_init!();
+    if let Err(why) = equalize_hist(gray, &mut equalized) {
+        return Err(FacialProcessingError::InternalError(why.to_string()));
+    }
+    let mut faces: Vector<Rect> = Vector::new();
+    if let Err(why) = classifier.detect_multi_scale(
+        &equalized,
+        &mut faces,
+        scale_factor,
+        min_neighbors,
+        0,
+        Size::default(),
+        Size::default(),
+    ) {
+        return Err(FacialProcessingError::InternalError(why.to_string()));
+    }
+    Ok(faces
+        .iter()
+        .map(|face| Detection::from(BoundingBox::from(face)))
+        .collect())
+}
@@ -1,32 +1,371 @@
-use crate::{error::FacialProcessingError, face_processor_trait::FaceProcessorTrait, utils::{eyes::Eye, face::FaceLandmark, misc::{BoundingBox, EulerAngles, Point2D}}};
-use image::{ImageBuffer, Rgb};
-use std::{
-    cell::{Cell, RefCell},
-    ops::Deref,
-    path::Path,
+use crate::{
+    error::FacialProcessingError,
+    face_processor_trait::FaceProcessorTrait,
+    pt_dist, pt_mdpt,
+    utils::{
+        detection::{non_max_suppression, Detection},
+        eyes::Iris,
+        face::FaceLandmark,
+        frame::Frame,
+        layout::LandmarkLayout,
+        misc::{BoundingBox, LeftRight, Point2D, SupportedProcesses},
+        model_config::{crop_padded, square_around, LandmarkModelConfig},
+    },
 };
+use image::{
+    imageops::{resize, FilterType},
+    ImageBuffer, Rgb,
+};
+use std::path::Path;
+use tflite::{ops::builtin::BuiltinOpResolver, FlatBufferModel, Interpreter, InterpreterBuilder};
+
+type TfInterpreter = Interpreter<'static, BuiltinOpResolver>;
+
+const DETECTOR_WIDTH: u32 = 320;
+const DETECTOR_HEIGHT: u32 = 240;
+// RFB-320's feature map sizes (width, height), and the anchor sizes in input pixels on each.
+const FEATURE_MAPS: [[usize; 2]; 4] = [[40, 30], [20, 15], [10, 8], [5, 4]];
+const MIN_BOXES: [&[f32]; 4] = [
+    &[10.0, 16.0, 24.0],
+    &[32.0, 48.0],
+    &[64.0, 96.0],
+    &[128.0, 192.0, 256.0],
+];
+const CENTER_VARIANCE: f32 = 0.1;
+const SIZE_VARIANCE: f32 = 0.2;
+const NMS_THRESHOLD: f32 = 0.3;
+const DEFAULT_CONFIDENCE: f32 = 0.6;
+
+const IRIS_INPUT_SIZE: u32 = 64;
+// The eye is scaled to be this many pixels wide in the iris model's input.
+const IRIS_EYE_WIDTH: f64 = 23.0;
+// The iris output is 5 (x, y, z) points: the center, then 4 on the edge.
+const IRIS_OUTPUT_LEN: usize = 15;
 
 pub struct OpenVTFaceProcessor {
+    face_detector: TfInterpreter,
+    face_alignment: TfInterpreter,
+    face_eyesolator: TfInterpreter,
+    alignment_config: LandmarkModelConfig,
+    // (center x, center y, width, height), relative to the detector input
+    anchors: Vec<[f32; 4]>,
+    confidence: f32,
 }
 
 impl OpenVTFaceProcessor {
-    pub fn new()
+    /// Load `RFB-320.tflite`, `coor_2d106.tflite` and `iris_localization.tflite` from the
+    /// OpenVTuber weights.
+    pub fn new<P: AsRef<Path>>(
+        face_detector_path: P,
+        face_alignment_path: P,
+        face_eyesolator_path: P,
+    ) -> Result<Self, FacialProcessingError> {
+        let face_detector = match load_interpreter(face_detector_path) {
+            Ok(i) => i,
+            Err(why) => return Err(why),
+        };
+        let alignment_config = LandmarkModelConfig::openvtuber_106(
+            face_alignment_path.as_ref().to_string_lossy().to_string(),
+        );
+        let face_alignment = match load_interpreter(face_alignment_path) {
+            Ok(i) => i,
+            Err(why) => return Err(why),
+        };
+        let face_eyesolator = match load_interpreter(face_eyesolator_path) {
+            Ok(i) => i,
+            Err(why) => return Err(why),
+        };
+
+        Ok(OpenVTFaceProcessor {
+            face_detector,
+            face_alignment,
+            face_eyesolator,
+            alignment_config,
+            anchors: generate_anchors(),
+            confidence: DEFAULT_CONFIDENCE,
+        })
+    }
+
+    pub fn detect_faces(
+        &mut self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        let input = resize(data, DETECTOR_WIDTH, DETECTOR_HEIGHT, FilterType::Triangle);
+        let mut tensor: Vec<f32> = input.as_raw().iter().map(|v| *v as f32).collect();
+        min_max_normalize(&mut tensor);
+        let outputs = match run_interpreter(&mut self.face_detector, &tensor) {
+            Ok(o) => o,
+            Err(why) => return Err(why),
+        };
+
+        let anchor_count = self.anchors.len();
+        let regressions = outputs.iter().find(|o| o.len() == anchor_count * 4);
+        let scores = outputs.iter().find(|o| o.len() == anchor_count * 2);
+        let (regressions, scores) = match (regressions, scores) {
+            (Some(r), Some(s)) => (r, s),
+            _ => {
+                return Err(FacialProcessingError::InternalError(format!(
+                    "Expected box and score outputs for {} anchors",
+                    anchor_count
+                )))
+            }
+        };
+
+        let (width, height) = (data.width() as f32, data.height() as f32);
+        let mut candidates: Vec<Detection> = vec![];
+        for ((anchor, reg), score) in self
+            .anchors
+            .iter()
+            .zip(regressions.chunks_exact(4))
+            .zip(scores.chunks_exact(2))
+        {
+            if score[1] <= self.confidence {
+                continue;
+            }
+            let center_x = reg[0] * CENTER_VARIANCE * anchor[2] + anchor[0];
+            let center_y = reg[1] * CENTER_VARIANCE * anchor[3] + anchor[1];
+            let half_w = (reg[2] * SIZE_VARIANCE).exp() * anchor[2] / 2.0;
+            let half_h = (reg[3] * SIZE_VARIANCE).exp() * anchor[3] / 2.0;
+            candidates.push(Detection::new(
+                BoundingBox {
+                    x_minumum: ((center_x - half_w).max(0.0) * width) as i32,
+                    x_maximum: ((center_x + half_w).min(1.0) * width) as i32,
+                    y_minumum: ((center_y - half_h).max(0.0) * height) as i32,
+                    y_maximum: ((center_y + half_h).min(1.0) * height) as i32,
+                },
+                score[1],
+            ));
+        }
+
+        Ok(non_max_suppression(candidates, NMS_THRESHOLD))
+    }
+
+    pub fn landmark_face(
+        &mut self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        let config = &self.alignment_config;
+        let (input, crop_box) = config.crop(data, bbox);
+        let outputs = match run_interpreter(
+            &mut self.face_alignment,
+            &config.preprocessing.tensor_data(&input),
+        ) {
+            Ok(o) => o,
+            Err(why) => return Err(why),
+        };
+        let raw = match outputs.first() {
+            Some(r) => r,
+            None => {
+                return Err(FacialProcessingError::InternalError(
+                    "Alignment model has no outputs".to_string(),
+                ))
+            }
+        };
+
+        let points = raw
+            .chunks_exact(2)
+            .take(config.layout.point_count())
+            .map(|pt| config.decode_point(pt[0], pt[1], crop_box))
+            .collect();
+        FaceLandmark::new(bbox, points, config.layout.clone())
+    }
+
+    /// Find the iris of one eye with the eyesolator.
+    pub fn localize_iris(
+        &mut self,
+        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        landmark: &FaceLandmark,
+        side: LeftRight,
+    ) -> Result<Iris, FacialProcessingError> {
+        let eye = landmark.eye_landmarks(side)?;
+        let corners = landmark.eye_corners(side)?;
+        let eye_center = pt_mdpt![eye[0], eye[1], eye[2], eye[3], eye[4], eye[5]];
+        let eye_width = pt_dist!(corners[0], corners[1]).max(1.0);
+
+        let crop_box = square_around(
+            eye_center,
+            IRIS_INPUT_SIZE as f64 * eye_width / IRIS_EYE_WIDTH,
+        );
+        let input = crop_padded(data, crop_box, IRIS_INPUT_SIZE, IRIS_INPUT_SIZE);
+        let mut tensor: Vec<f32> = input.as_raw().iter().map(|v| *v as f32).collect();
+        min_max_normalize(&mut tensor);
+        let outputs = match run_interpreter(&mut self.face_eyesolator, &tensor) {
+            Ok(o) => o,
+            Err(why) => return Err(why),
+        };
+        let iris = match outputs.iter().find(|o| o.len() == IRIS_OUTPUT_LEN) {
+            Some(i) => i,
+            None => {
+                return Err(FacialProcessingError::InternalError(
+                    "Eyesolator has no iris output".to_string(),
+                ))
+            }
+        };
+
+        let scale = (crop_box.x_maximum - crop_box.x_minumum) as f64 / IRIS_INPUT_SIZE as f64;
+        let to_image = |point: &[f32]| {
+            Point2D::new(
+                crop_box.x_minumum as f64 + point[0] as f64 * scale,
+                crop_box.y_minumum as f64 + point[1] as f64 * scale,
+            )
+        };
+        let mut points = iris.chunks_exact(3).map(to_image);
+        let center = match points.next() {
+            Some(c) => c,
+            None => Point2D::default(),
+        };
+        let radius = points
+            .map(|edge| pt_dist!(center, edge))
+            .fold(0.0, f64::max);
+        Ok(Iris { center, radius })
+    }
 }
 
-impl FaceProcessorTrait for OpenVTFaceProcessor{
-    fn init(&self, cpu: i16, confidence: f32) -> Result<(), FacialProcessingError> {
-        todo!()
+impl FaceProcessorTrait for OpenVTFaceProcessor {
+    fn init(
+        &mut self,
+        threads: Option<i16>,
+        confidence: Option<f32>,
+    ) -> Result<(), FacialProcessingError> {
+        if let Some(threads) = threads {
+            for interpreter in [
+                &mut self.face_detector,
+                &mut self.face_alignment,
+                &mut self.face_eyesolator,
+            ]
+            .iter_mut()
+            {
+                interpreter.set_num_threads(threads as i32);
+            }
+        }
+        if let Some(confidence) = confidence {
+            self.confidence = confidence;
+        }
+        Ok(())
     }
 
-    fn get_face_detections(&self, data: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<BoundingBox> {
-        todo!()
+    fn get_face_detections(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        self.detect_faces(frame.image())
     }
 
     fn get_face_landmark(
-        &self,
-        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        &mut self,
+        frame: &Frame,
         bbox: BoundingBox,
-    ) -> FaceLandmark {
-        todo!()
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        self.landmark_face(frame.image(), bbox)
+    }
+
+    fn get_iris(
+        &mut self,
+        frame: &Frame,
+        landmark: &FaceLandmark,
+        side: LeftRight,
+    ) -> Result<Iris, FacialProcessingError> {
+        self.localize_iris(frame.image(), landmark, side)
+    }
+
+    fn supported_processes(&self) -> Vec<SupportedProcesses> {
+        vec![
+            SupportedProcesses::Detection,
+            SupportedProcesses::Alignment,
+            SupportedProcesses::Eyesolation,
+        ]
+    }
+
+    fn landmark_layout(&self) -> LandmarkLayout {
+        self.alignment_config.layout.clone()
+    }
+}
+
+fn generate_anchors() -> Vec<[f32; 4]> {
+    let mut anchors = vec![];
+    for (feature_map, min_boxes) in FEATURE_MAPS.iter().zip(MIN_BOXES.iter()) {
+        for y in 0..feature_map[1] {
+            for x in 0..feature_map[0] {
+                for min_box in min_boxes.iter() {
+                    anchors.push([
+                        ((x as f32 + 0.5) / feature_map[0] as f32).min(1.0),
+                        ((y as f32 + 0.5) / feature_map[1] as f32).min(1.0),
+                        (min_box / DETECTOR_WIDTH as f32).min(1.0),
+                        (min_box / DETECTOR_HEIGHT as f32).min(1.0),
+                    ]);
+                }
+            }
+        }
+    }
+    anchors
+}
+
+// Stretch the values to -1..1, like `cv2.normalize` with `NORM_MINMAX`.
+fn min_max_normalize(data: &mut [f32]) {
+    let min = data.iter().cloned().fold(f32::INFINITY, f32::min);
+    let max = data.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let range = if max > min { max - min } else { 1.0 };
+    for value in data.iter_mut() {
+        *value = (*value - min) / range * 2.0 - 1.0;
+    }
+}
+
+fn load_interpreter<P: AsRef<Path>>(path: P) -> Result<TfInterpreter, FacialProcessingError> {
+    let model = match FlatBufferModel::build_from_file(path) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InitializeError(why.to_string())),
+    };
+    let builder = match InterpreterBuilder::new(model, BuiltinOpResolver::default()) {
+        Ok(b) => b,
+        Err(why) => return Err(FacialProcessingError::InitializeError(why.to_string())),
+    };
+    let mut interpreter = match builder.build() {
+        Ok(i) => i,
+        Err(why) => return Err(FacialProcessingError::InitializeError(why.to_string())),
+    };
+    if let Err(why) = interpreter.allocate_tensors() {
+        return Err(FacialProcessingError::InitializeError(why.to_string()));
+    }
+    Ok(interpreter)
+}
+
+// Run on `input` and copy out every output.
+fn run_interpreter(
+    interpreter: &mut TfInterpreter,
+    input: &[f32],
+) -> Result<Vec<Vec<f32>>, FacialProcessingError> {
+    let input_index = match interpreter.inputs().first() {
+        Some(i) => *i,
+        None => {
+            return Err(FacialProcessingError::InternalError(
+                "Model has no inputs".to_string(),
+            ))
+        }
+    };
+    match interpreter.tensor_data_mut::<f32>(input_index) {
+        Ok(tensor) => {
+            if tensor.len() != input.len() {
+                return Err(FacialProcessingError::InternalError(format!(
+                    "Model expects {} input values, got {}",
+                    tensor.len(),
+                    input.len()
+                )));
+            }
+            tensor.copy_from_slice(input);
+        }
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    }
+    if let Err(why) = interpreter.invoke() {
+        return Err(FacialProcessingError::InternalError(why.to_string()));
+    }
+
+    let mut outputs = vec![];
+    for index in interpreter.outputs().to_vec() {
+        match interpreter.tensor_data::<f32>(index) {
+            Ok(data) => outputs.push(data.to_vec()),
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        }
     }
+    Ok(outputs)
 }
@@ -1,3 +1,4 @@
+use crate::utils::misc::SupportedProcesses;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -10,4 +11,26 @@ pub enum FacialProcessingError {
     InitializeError(String),
     #[error("Internal Error: {0}")]
     InternalError(String),
+    #[error("Landmark {index} is missing, only {available} points are available")]
+    MissingLandmark { index: usize, available: usize },
+    #[error("{layout} landmarks have no {region}")]
+    UnsupportedRegion {
+        region: &'static str,
+        layout: String,
+    },
+    #[error("Invalid landmark: {0}")]
+    InvalidLandmark(String),
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(String),
+    #[error("Invalid camera intrinsics: {0}")]
+    InvalidIntrinsics(String),
+    #[error("Invalid identity name: {0:?}")]
+    InvalidIdentityName(String),
+    #[error("{0:?} is not supported by this backend")]
+    UnsupportedProcess(SupportedProcesses),
+    #[error("{stage:?} stage: {reason}")]
+    IncompatibleStage {
+        stage: SupportedProcesses,
+        reason: String,
+    },
 }
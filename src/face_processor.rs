@@ -2,50 +2,82 @@
 
 #[cfg(feature = "dlib")]
 use crate::backends::dlib::dlib_processor::DLibProcessor;
+#[cfg(feature = "onnx")]
+use crate::backends::onnx::onnx_processor::OnnxProcessor;
+#[cfg(feature = "opencv_backend")]
+use crate::backends::opencv::opencv_processor::OpenCVProcessor;
 #[cfg(feature = "openvtuber")]
 use crate::backends::openvtuber::openvt_processor::OpenVTFaceProcessor;
 // Do not remove the `PnPArguments, Point2D` imports. They are cursed. It will not compile on downstream users otherwise. 
-use crate::{error::FacialProcessingError, face_processor_trait::FaceProcessorTrait, utils::{eyes::Eye, face::FaceLandmark, misc::{BackendProviders, BoundingBox, EulerAngles, ImageScale, LeftRight, PnPSolver, PnPArguments, Point2D}}};
-use image::{imageops::FilterType, ImageBuffer, Rgb};
+use crate::{error::FacialProcessingError, face_processor_trait::FaceProcessorTrait, utils::{calibration::CameraIntrinsics, detection::{Detection, DetectionFilter}, eyes::{Eye, Iris}, face::FaceLandmark, face_model::FaceModel3D, frame::Frame, identity::{FaceEmbedding, IdentityGallery, IdentityMatch}, layout::LandmarkLayout, misc::{BackendProviders, BoundingBox, ImageScale, LeftRight, PnPMethod, PnPSolver, PnPArguments, Point2D, SupportedProcesses}, mouth::Mouth, pose::{HeadPose, PoseTracker}}};
+use image::imageops::{resize, FilterType};
+use opencv::core::Mat;
+use std::collections::HashMap;
 
 pub struct FaceProcessorBuilder {
     backend: BackendProviders,
+    stage_backends: HashMap<SupportedProcesses, BackendProviders>,
     desired_threads: Option<i16>,
+    detection_confidence: Option<f32>,
+    detection_filter: DetectionFilter,
     do_eye_calculations: bool,
     do_mouth_calculations: bool,
     eye_blink_ratio: f64,
     input_image_x: u32,
     input_image_y: u32,
     image_scale: Option<ImageScale>,
+    face_model: Option<FaceModel3D>,
+    camera_intrinsics: Option<CameraIntrinsics>,
+    pnp_method: Option<PnPMethod>,
+    min_pose_confidence: f64,
+    identity_gallery: IdentityGallery,
 }
 
 impl FaceProcessorBuilder {
     pub fn new() -> Self {
         FaceProcessorBuilder {
             backend: BackendProviders::None,
+            stage_backends: HashMap::new(),
             desired_threads: None,
+            detection_confidence: None,
+            detection_filter: DetectionFilter::default(),
             do_eye_calculations: true,
             do_mouth_calculations: true,
             eye_blink_ratio: 0.2,
             input_image_x: 640,
             input_image_y: 480,
             image_scale: None,
+            face_model: None,
+            camera_intrinsics: None,
+            pnp_method: None,
+            min_pose_confidence: 0.25,
+            identity_gallery: IdentityGallery::default(),
         }
     }
 
     pub fn with_eye_calibration(self, eye_blink_ratio: f64) -> Self {
         FaceProcessorBuilder {
             backend: self.backend,
+            stage_backends: self.stage_backends,
             desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
+    /// Resize frames to `target_x` by `target_y` before detection and landmarking. Boxes and
+    /// landmarks are mapped back to the full frame.
     pub fn with_image_scale(self, target_x: u32, target_y: u32, method: FilterType) -> Self {
         let image_scale = Some(ImageScale {
             target_x,
@@ -54,130 +86,390 @@ impl FaceProcessorBuilder {
         });
         FaceProcessorBuilder {
             backend: self.backend,
+            stage_backends: self.stage_backends,
             desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
     pub fn with_input(self, input_image_x: u32, input_image_y: u32) -> Self {
         FaceProcessorBuilder {
             backend: self.backend,
+            stage_backends: self.stage_backends,
             desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x,
             input_image_y,
             image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
     pub fn with_backend(self, backend: BackendProviders) -> Self {
         FaceProcessorBuilder {
             backend,
+            stage_backends: self.stage_backends,
             desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
+    /// How many threads the backends may use. What that covers depends on the backend: OpenCV and
+    /// OpenVTuber's TFLite models use them inside inference, while dlib and ONNX (tract has no
+    /// threads of its own) only landmark the faces of a frame in parallel and detect on one thread.
     pub fn with_desired_threads(self, threads: i16) -> Self {
         let desired_threads = Some(threads);
         FaceProcessorBuilder {
             backend: self.backend,
+            stage_backends: self.stage_backends,
             desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    /// The score a detection needs to be kept, for the backends whose detectors score their boxes.
+    /// Without this each backend keeps its own default.
+    pub fn with_detection_confidence(self, confidence: f32) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: Some(confidence),
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    /// Drop small faces, or keep only the highest scoring ones, after every detection.
+    pub fn with_detection_filter(self, detection_filter: DetectionFilter) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
     pub fn with_eye_calculations(self, do_eye_calculations: bool) -> Self {
         FaceProcessorBuilder {
             backend: self.backend,
+            stage_backends: self.stage_backends,
             desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
     pub fn with_mouth_calculations(self, do_mouth_calculations: bool) -> Self {
         FaceProcessorBuilder {
             backend: self.backend,
+            stage_backends: self.stage_backends,
             desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    pub fn with_face_model(self, face_model: FaceModel3D) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: Some(face_model),
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    /// Calibrated intrinsics, scaled to the `with_input` resolution by `build`. `build` fails if
+    /// the calibration has another aspect ratio, as a cropped mode needs its own calibration.
+    pub fn with_camera_intrinsics(self, camera_intrinsics: CameraIntrinsics) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: Some(camera_intrinsics),
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    pub fn with_pnp_method(self, pnp_method: PnPMethod) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: Some(pnp_method),
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    pub fn with_min_pose_confidence(self, min_pose_confidence: f64) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence,
+            identity_gallery: self.identity_gallery,
+        }
+    }
+
+    pub fn with_identity_gallery(self, identity_gallery: IdentityGallery) -> Self {
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends: self.stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery,
+        }
+    }
+
+    /// Run one stage on a different backend than the one from `with_backend`, for example
+    /// `Detection` on OpenCV's SSD with dlib's 68 point model for `Alignment`. Stages that are
+    /// given the same provider share one instance of it. `Detection` and `Alignment` need a backend,
+    /// the other stages are left without one if neither this nor `with_backend` gives them one, so
+    /// OpenCV detection, dlib alignment and OpenVTuber's eyesolator can be set up without
+    /// `with_backend` at all.
+    pub fn with_stage_backend(self, stage: SupportedProcesses, backend: BackendProviders) -> Self {
+        let mut stage_backends = self.stage_backends;
+        stage_backends.insert(stage, backend);
+        FaceProcessorBuilder {
+            backend: self.backend,
+            stage_backends,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            do_eye_calculations: self.do_eye_calculations,
+            do_mouth_calculations: self.do_mouth_calculations,
+            eye_blink_ratio: self.eye_blink_ratio,
+            input_image_x: self.input_image_x,
+            input_image_y: self.input_image_y,
+            image_scale: self.image_scale,
+            face_model: self.face_model,
+            camera_intrinsics: self.camera_intrinsics,
+            pnp_method: self.pnp_method,
+            min_pose_confidence: self.min_pose_confidence,
+            identity_gallery: self.identity_gallery,
         }
     }
 
-    #[cfg(feature = "dlib")]
     pub fn build(self) -> Result<FaceProcessor, FacialProcessingError> {
-        let backend_held: Box<dyn FaceProcessorTrait> = match self.backend.clone() {
-            // BackendProviders::OpenVTuber {
-            //     face_detector_path,
-            //     face_alignment_path,
-            //     face_eyesolator_path,
-            // } => {
-            //     match OpenVTFaceProcessor::new(
-            //         face_detector_path,
-            //         face_alignment_path,
-            //         face_eyesolator_path,
-            //     ) {
-            //         Ok(process) => Box::new(process),
-            //         Err(why) => return Err(why),
-            //     }
-            // }
-            // TODO: Fix
-            BackendProviders::DLib {
-                face_alignment_path,
-            } => match DLibProcessor::new(face_alignment_path) {
-                Ok(process) => Box::new(process),
+        // one instance per distinct provider. Stages without a provider get no backend, the
+        // required ones are caught by validate_stages
+        let mut providers: Vec<BackendProviders> = vec![];
+        let mut stages = HashMap::new();
+        for stage in SupportedProcesses::all().iter() {
+            let provider = self.stage_backends.get(stage).unwrap_or(&self.backend);
+            if let BackendProviders::None = provider {
+                stages.insert(*stage, None);
+                continue;
+            }
+            let index = match providers.iter().position(|p| p == provider) {
+                Some(index) => index,
+                None => {
+                    providers.push(provider.clone());
+                    providers.len() - 1
+                }
+            };
+            stages.insert(*stage, Some(index));
+        }
+        let mut backends = Vec::with_capacity(providers.len());
+        for provider in providers {
+            let mut backend = match create_backend(provider) {
+                Ok(backend) => backend,
                 Err(why) => return Err(why),
-            },
-            _ => {
-                return Err(FacialProcessingError::InitializeError(
-                    "unsupported!".to_string(),
-                ))
+            };
+            if let Err(why) = backend.init(self.desired_threads, self.detection_confidence) {
+                return Err(why);
             }
-        };
+            backends.push(backend);
+        }
 
-        let pnp = PnPSolver::new(
+        let mut pnp = match PnPSolver::new(
             Point2D::new(self.input_image_x as f64, self.input_image_y as f64),
-            None,
+            self.pnp_method,
             PnPArguments::NoRandsc,
-        )
-        .unwrap();
+            self.face_model,
+        ) {
+            Ok(p) => p,
+            Err(why) => return Err(why),
+        };
+        // calibrations are often done at another resolution than the processor is fed
+        if let Some(intrinsics) = &self.camera_intrinsics {
+            let scaled = match intrinsics.scaled_to(Point2D::new(
+                self.input_image_x as f64,
+                self.input_image_y as f64,
+            )) {
+                Ok(i) => i,
+                Err(why) => return Err(why),
+            };
+            if let Err(why) = pnp.set_intrinsics(&scaled) {
+                return Err(why);
+            }
+        }
+
+        if let Err(why) = validate_stages(&backends, &stages, &self.stage_backends, &pnp) {
+            return Err(why);
+        }
 
         Ok(FaceProcessor {
             backend_setting: self.backend,
-            backend_held,
+            desired_threads: self.desired_threads,
+            detection_confidence: self.detection_confidence,
+            detection_filter: self.detection_filter,
+            stage_settings: self.stage_backends,
+            backends,
+            stages,
             do_eye_calculations: self.do_eye_calculations,
             do_mouth_calculations: self.do_mouth_calculations,
             eye_blink_ratio: self.eye_blink_ratio,
             input_image_x: self.input_image_x,
             input_image_y: self.input_image_y,
             image_scale: self.image_scale,
-            pnp,
+            pnp: PoseTracker::new(pnp, self.min_pose_confidence),
+            identities: self.identity_gallery,
+            tracked_identities: HashMap::new(),
         })
     }
 }
@@ -188,47 +480,507 @@ impl Default for FaceProcessorBuilder {
     }
 }
 
+fn create_backend(
+    provider: BackendProviders,
+) -> Result<Box<dyn FaceProcessorTrait>, FacialProcessingError> {
+    let backend: Box<dyn FaceProcessorTrait> = match provider {
+        #[cfg(feature = "openvtuber")]
+        BackendProviders::OpenVTuber {
+            face_detector_path,
+            face_alignment_path,
+            face_eyesolator_path,
+        } => {
+            match OpenVTFaceProcessor::new(
+                face_detector_path,
+                face_alignment_path,
+                face_eyesolator_path,
+            ) {
+                Ok(process) => Box::new(process),
+                Err(why) => return Err(why),
+            }
+        }
+        #[cfg(feature = "dlib")]
+        BackendProviders::DLib {
+            face_detector,
+            face_alignment_path,
+            landmark_layout,
+            face_encoder_path,
+        } => match DLibProcessor::new(
+            face_detector,
+            face_alignment_path,
+            landmark_layout,
+            face_encoder_path,
+        ) {
+            Ok(process) => Box::new(process),
+            Err(why) => return Err(why),
+        },
+        #[cfg(feature = "onnx")]
+        BackendProviders::Onnx { detector, landmark } => {
+            match OnnxProcessor::new(detector, landmark) {
+                Ok(process) => Box::new(process),
+                Err(why) => return Err(why),
+            }
+        }
+        #[cfg(feature = "opencv_backend")]
+        BackendProviders::OpenCV {
+            detector,
+            facemark_lbf_path,
+        } => match OpenCVProcessor::new(detector, facemark_lbf_path) {
+            Ok(process) => Box::new(process),
+            Err(why) => return Err(why),
+        },
+        BackendProviders::Custom(custom) => match custom.create() {
+            Ok(process) => process,
+            Err(why) => return Err(why),
+        },
+        _ => {
+            return Err(FacialProcessingError::InitializeError(
+                "unsupported!".to_string(),
+            ))
+        }
+    };
+    Ok(backend)
+}
+
+/// Check that every stage's backend can run it, and that the landmarks from `Alignment` have what
+/// the eye and pose stages need.
+fn validate_stages(
+    backends: &[Box<dyn FaceProcessorTrait>],
+    stages: &HashMap<SupportedProcesses, Option<usize>>,
+    stage_backends: &HashMap<SupportedProcesses, BackendProviders>,
+    pnp: &PnPSolver,
+) -> Result<(), FacialProcessingError> {
+    let supports = |stage: SupportedProcesses| match stages[&stage] {
+        Some(index) => backends[index].supported_processes().contains(&stage),
+        None => false,
+    };
+
+    // detection and alignment always run, the rest only have to work if they were given a backend
+    let required = [SupportedProcesses::Detection, SupportedProcesses::Alignment];
+    for stage in required.iter() {
+        if stages[stage].is_none() {
+            return Err(FacialProcessingError::IncompatibleStage {
+                stage: *stage,
+                reason: "no backend was given for it".to_string(),
+            });
+        }
+    }
+    for stage in required.iter().chain(stage_backends.keys()) {
+        if stages[stage].is_some() && !supports(*stage) {
+            return Err(FacialProcessingError::IncompatibleStage {
+                stage: *stage,
+                reason: "its backend does not support it".to_string(),
+            });
+        }
+    }
+
+    let layout = match stages[&SupportedProcesses::Alignment] {
+        Some(index) => backends[index].landmark_layout(),
+        None => return Ok(()),
+    };
+    if supports(SupportedProcesses::Eyesolation)
+        && (layout.eye(LeftRight::Left).is_none() || layout.eye(LeftRight::Right).is_none())
+    {
+        return Err(FacialProcessingError::IncompatibleStage {
+            stage: SupportedProcesses::Eyesolation,
+            reason: format!("{} landmarks have no eye outlines to find the irises in", layout.name()),
+        });
+    }
+    if !supports(SupportedProcesses::PoseEstimation)
+        && *pnp.face_model().layout() != layout
+        && FaceModel3D::fallback_for(&layout).is_none()
+    {
+        return Err(FacialProcessingError::IncompatibleStage {
+            stage: SupportedProcesses::PoseEstimation,
+            reason: format!(
+                "the face model is for {} landmarks, and there is no fallback model for {} landmarks",
+                pnp.face_model().layout().name(),
+                layout.name()
+            ),
+        });
+    }
+    Ok(())
+}
+
 pub struct FaceProcessor {
     backend_setting: BackendProviders,
-    backend_held: Box<dyn FaceProcessorTrait>,
+    desired_threads: Option<i16>,
+    detection_confidence: Option<f32>,
+    detection_filter: DetectionFilter,
+    stage_settings: HashMap<SupportedProcesses, BackendProviders>,
+    backends: Vec<Box<dyn FaceProcessorTrait>>,
+    // index into `backends` for every stage, `None` for optional stages left without a backend
+    stages: HashMap<SupportedProcesses, Option<usize>>,
     do_eye_calculations: bool,
     do_mouth_calculations: bool,
     eye_blink_ratio: f64,
     input_image_x: u32,
     input_image_y: u32,
     image_scale: Option<ImageScale>,
-    pnp: PnPSolver,
+    pnp: PoseTracker,
+    identities: IdentityGallery,
+    tracked_identities: HashMap<usize, IdentityMatch>,
 }
 
 impl FaceProcessor {
-    pub fn calculate_face_bboxes(&self, image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<BoundingBox> {
-        self.backend_held.get_face_detections(image)
+    /// The faces in `frame` that pass the detection filter, highest score first.
+    pub fn calculate_detections(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        let detections = match self.scaled_frame(frame) {
+            Some((scaled, x, y)) => self
+                .stage_mut(SupportedProcesses::Detection)?
+                .get_face_detections(&scaled)?
+                .into_iter()
+                .map(|detection| detection.scaled(x, y))
+                .collect(),
+            None => self
+                .stage_mut(SupportedProcesses::Detection)?
+                .get_face_detections(frame)?,
+        };
+        Ok(self.detection_filter.apply(detections))
+    }
+
+    pub fn calculate_face_bboxes(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<BoundingBox>, FacialProcessingError> {
+        let detections = self.calculate_detections(frame)?;
+        Ok(detections.into_iter().map(|d| d.bbox).collect())
     }
 
     pub fn calculate_landmark(
-        &self,
-        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        &mut self,
+        frame: &Frame,
         bbox: BoundingBox,
-    ) -> FaceLandmark {
-        self.backend_held.get_face_landmark(image, bbox)
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        match self.scaled_frame(frame) {
+            Some((scaled, x, y)) => Ok(self
+                .stage_mut(SupportedProcesses::Alignment)?
+                .get_face_landmark(&scaled, bbox.scaled(1.0 / x, 1.0 / y))?
+                .scaled(x, y)),
+            None => self
+                .stage_mut(SupportedProcesses::Alignment)?
+                .get_face_landmark(frame, bbox),
+        }
+    }
+
+    /// Landmark every face in `bboxes` in one backend call.
+    pub fn calculate_landmarks(
+        &mut self,
+        frame: &Frame,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        match self.scaled_frame(frame) {
+            Some((scaled, x, y)) => {
+                let bboxes: Vec<BoundingBox> = bboxes
+                    .iter()
+                    .map(|bbox| bbox.scaled(1.0 / x, 1.0 / y))
+                    .collect();
+                let landmarks = self
+                    .stage_mut(SupportedProcesses::Alignment)?
+                    .get_face_landmarks(&scaled, &bboxes)?;
+                Ok(landmarks
+                    .into_iter()
+                    .map(|landmark| landmark.scaled(x, y))
+                    .collect())
+            }
+            None => self
+                .stage_mut(SupportedProcesses::Alignment)?
+                .get_face_landmarks(frame, bboxes),
+        }
+    }
+
+    /// `calculate_detections` for an 8 bit BGR, BGRA or gray `Mat`, such as one from a `VideoCapture`.
+    /// The `_mat` functions only cover detection and landmarking. For the other stages, or to run
+    /// several stages on one `Mat`, borrow it once with `Frame::from_mat` and pass the frame along.
+    pub fn calculate_detections_mat(
+        &mut self,
+        mat: &Mat,
+    ) -> Result<Vec<Detection>, FacialProcessingError> {
+        let frame = Frame::from_mat(mat)?;
+        self.calculate_detections(&frame)
+    }
+
+    pub fn calculate_face_bboxes_mat(
+        &mut self,
+        mat: &Mat,
+    ) -> Result<Vec<BoundingBox>, FacialProcessingError> {
+        let frame = Frame::from_mat(mat)?;
+        self.calculate_face_bboxes(&frame)
+    }
+
+    pub fn calculate_landmark_mat(
+        &mut self,
+        mat: &Mat,
+        bbox: BoundingBox,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        let frame = Frame::from_mat(mat)?;
+        self.calculate_landmark(&frame, bbox)
+    }
+
+    pub fn calculate_landmarks_mat(
+        &mut self,
+        mat: &Mat,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        let frame = Frame::from_mat(mat)?;
+        self.calculate_landmarks(&frame, bboxes)
     }
 
+    /// Uses the backend's own pose estimation if it has one, otherwise the shared `PnPSolver`.
     pub fn calculate_pnp(
-        &self,
-        _image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        &mut self,
+        frame: &Frame,
+        landmark: FaceLandmark,
+    ) -> Result<HeadPose, FacialProcessingError> {
+        if self.supports(SupportedProcesses::PoseEstimation) {
+            return self
+                .stage_mut(SupportedProcesses::PoseEstimation)?
+                .get_pnp_forward(frame, landmark);
+        }
+        self.pnp.solver().forward(landmark)
+    }
+
+    /// Like `calculate_pnp`, but warm-started from the previous pose of the face with this id.
+    /// Call `end_frame` with the ids still being tracked so lost faces get reset.
+    pub fn calculate_pnp_tracked(
+        &mut self,
+        face_id: usize,
         landmark: FaceLandmark,
-    ) -> Result<EulerAngles, FacialProcessingError> {
-        self.pnp.forward(landmark)
+    ) -> Result<HeadPose, FacialProcessingError> {
+        self.pnp.forward(face_id, landmark)
+    }
+
+    /// Set whether tracked PnP also solves for the flipped pose and picks the more plausible one. On
+    /// by default; turning it off saves the two extra solves per face.
+    pub fn set_flip_suppression(&mut self, flip_suppression: bool) {
+        self.pnp.set_flip_suppression(flip_suppression);
+    }
+
+    /// Drop the pose state and identity of every face not in `face_ids`.
+    pub fn end_frame(&mut self, face_ids: &[usize]) {
+        self.pnp.retain(face_ids);
+        self.tracked_identities
+            .retain(|face_id, _| face_ids.contains(face_id));
+    }
+
+    /// Compute a recognition embedding for the face in `bbox`. `UnsupportedProcess` if the
+    /// `Recognition` stage has no backend or its backend has no face encoder.
+    pub fn calculate_embedding(
+        &mut self,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<FaceEmbedding, FacialProcessingError> {
+        self.stage_mut(SupportedProcesses::Recognition)?
+            .get_face_embedding(frame, bbox)
+    }
+
+    /// Add the face in `bbox` to the gallery as `name`.
+    pub fn enroll_identity(
+        &mut self,
+        name: &str,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<(), FacialProcessingError> {
+        let embedding = self.calculate_embedding(frame, bbox)?;
+        self.identities.enroll(name, embedding)
+    }
+
+    /// Match the face in `bbox` against the gallery. `None` if nobody is close enough.
+    pub fn identify(
+        &mut self,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<Option<IdentityMatch>, FacialProcessingError> {
+        let embedding = self.calculate_embedding(frame, bbox)?;
+        Ok(self.identities.match_face(&embedding))
     }
 
+    /// Like `identify`, but also remembers the result for the face with this id until `end_frame`
+    /// drops it. See `tracked_identity`.
+    pub fn identify_tracked(
+        &mut self,
+        face_id: usize,
+        frame: &Frame,
+        bbox: BoundingBox,
+    ) -> Result<Option<IdentityMatch>, FacialProcessingError> {
+        let found = self.identify(frame, bbox)?;
+        match &found {
+            Some(m) => {
+                self.tracked_identities.insert(face_id, m.clone());
+            }
+            None => {
+                self.tracked_identities.remove(&face_id);
+            }
+        }
+        Ok(found)
+    }
+
+    /// The last identity matched for the face with this id.
+    pub fn tracked_identity(&self, face_id: usize) -> Option<&IdentityMatch> {
+        self.tracked_identities.get(&face_id)
+    }
+
+    /// Locate the iris of one eye with the eye backend, such as OpenVTuber's eyesolator.
+    /// `UnsupportedProcess` if the `Eyesolation` stage has no backend.
+    pub fn calculate_iris(
+        &mut self,
+        landmark: &FaceLandmark,
+        frame: &Frame,
+        side: LeftRight,
+    ) -> Result<Iris, FacialProcessingError> {
+        self.stage_mut(SupportedProcesses::Eyesolation)?
+            .get_iris(frame, landmark, side)
+    }
+
+    /// `None` if the landmark has no eye outlines, such as with dlib's 5 point model.
     pub fn calculate_eyes(
         &self,
         landmark: FaceLandmark,
-        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    ) -> [Eye; 2] {
-        let e1 = Eye::new(&landmark, LeftRight::Left, image);
-        let e2 = Eye::new(&landmark, LeftRight::Right, image);
-        [e1, e2]
+    ) -> Result<Option<[Eye; 2]>, FacialProcessingError> {
+        if landmark.layout().eye(LeftRight::Left).is_none()
+            || landmark.layout().eye(LeftRight::Right).is_none()
+        {
+            return Ok(None);
+        }
+        let e1 = Eye::new(&landmark, LeftRight::Left)?;
+        let e2 = Eye::new(&landmark, LeftRight::Right)?;
+        Ok(Some([e1, e2]))
+    }
+
+    /// `None` if mouth calculations are turned off, or the landmark has no mouth outlines, such as
+    /// with dlib's 5 point model.
+    pub fn calculate_mouth(
+        &self,
+        landmark: FaceLandmark,
+    ) -> Result<Option<Mouth>, FacialProcessingError> {
+        if !self.do_mouth_calculations
+            || landmark.layout().mouth_outer().is_none()
+            || landmark.layout().mouth_inner().is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(Mouth::new(&landmark)?))
+    }
+
+    /// Run every backend once on a black frame of the input size, so the first real frame is not
+    /// slowed down by lazy setup.
+    pub fn warmup(&mut self) -> Result<(), FacialProcessingError> {
+        for backend in self.backends.iter_mut() {
+            backend.warmup(self.input_image_x, self.input_image_y)?;
+        }
+        Ok(())
+    }
+
+    /// Shut every backend down. The processor should not be used afterwards.
+    pub fn shutdown(&mut self) -> Result<(), FacialProcessingError> {
+        for backend in self.backends.iter_mut() {
+            backend.shutdown()?;
+        }
+        Ok(())
+    }
+
+    // `frame` resized to the image scale, with the factors that map its coordinates back to
+    // `frame`. `None` without an image scale, or if the frame already has its size.
+    fn scaled_frame(&self, frame: &Frame) -> Option<(Frame<'static>, f64, f64)> {
+        let scale = self.image_scale?;
+        if scale.target_x == 0
+            || scale.target_y == 0
+            || (frame.width() == scale.target_x && frame.height() == scale.target_y)
+        {
+            return None;
+        }
+        let resized = resize(frame.image(), scale.target_x, scale.target_y, scale.method);
+        Some((
+            Frame::from(resized),
+            frame.width() as f64 / scale.target_x as f64,
+            frame.height() as f64 / scale.target_y as f64,
+        ))
+    }
+
+    fn stage(&self, stage: SupportedProcesses) -> Option<&dyn FaceProcessorTrait> {
+        match self.stages[&stage] {
+            Some(index) => Some(self.backends[index].as_ref()),
+            None => None,
+        }
+    }
+
+    fn stage_mut(&mut self, stage: SupportedProcesses) -> Result<&mut dyn FaceProcessorTrait, FacialProcessingError> {
+        match self.stages[&stage] {
+            Some(index) => Ok(self.backends[index].as_mut()),
+            None => Err(FacialProcessingError::UnsupportedProcess(stage)),
+        }
+    }
+
+    /// The stages run by the backend assigned to them.
+    pub fn supported_processes(&self) -> Vec<SupportedProcesses> {
+        SupportedProcesses::all()
+            .iter()
+            .copied()
+            .filter(|process| self.supports(*process))
+            .collect()
+    }
+
+    /// Whether the backend assigned to `process` runs it itself.
+    pub fn supports(&self, process: SupportedProcesses) -> bool {
+        match self.stage(process) {
+            Some(backend) => backend.supported_processes().contains(&process),
+            None => false,
+        }
+    }
+
+    /// The markup of the landmarks the alignment backend returns.
+    pub fn landmark_layout(&self) -> LandmarkLayout {
+        // build does not let alignment go without a backend
+        match self.stage(SupportedProcesses::Alignment) {
+            Some(backend) => backend.landmark_layout(),
+            None => LandmarkLayout::default(),
+        }
+    }
+
+    /// Get a reference to the providers of the stages that do not use the main backend.
+    pub fn stage_settings(&self) -> &HashMap<SupportedProcesses, BackendProviders> {
+        &self.stage_settings
+    }
+
+    /// Get the thread count the backends were set to, if any.
+    pub fn desired_threads(&self) -> Option<i16> {
+        self.desired_threads
+    }
+
+    /// Set how many threads the backends may use, see `FaceProcessorBuilder::with_desired_threads`.
+    pub fn set_desired_threads(&mut self, threads: i16) -> Result<(), FacialProcessingError> {
+        for backend in self.backends.iter_mut() {
+            backend.init(Some(threads), None)?;
+        }
+        self.desired_threads = Some(threads);
+        Ok(())
+    }
+
+    /// Get the detection confidence the backends were set to, if any.
+    pub fn detection_confidence(&self) -> Option<f32> {
+        self.detection_confidence
+    }
+
+    /// Set the score a detection needs to be kept.
+    pub fn set_detection_confidence(&mut self, confidence: f32) -> Result<(), FacialProcessingError> {
+        for backend in self.backends.iter_mut() {
+            backend.init(None, Some(confidence))?;
+        }
+        self.detection_confidence = Some(confidence);
+        Ok(())
+    }
+
+    /// Get a reference to the face processor's detection filter.
+    pub fn detection_filter(&self) -> &DetectionFilter {
+        &self.detection_filter
+    }
+
+    /// Set the face processor's detection filter.
+    pub fn set_detection_filter(&mut self, detection_filter: DetectionFilter) {
+        self.detection_filter = detection_filter;
     }
 
     /// Get a reference to the face processor's backend setting.
@@ -275,4 +1027,55 @@ impl FaceProcessor {
     pub fn set_image_scale(&mut self, image_scale: Option<ImageScale>) {
         self.image_scale = image_scale;
     }
+
+    /// Set the face processor's camera intrinsics, scaled to the input resolution.
+    pub fn set_camera_intrinsics(
+        &mut self,
+        intrinsics: &CameraIntrinsics,
+    ) -> Result<(), FacialProcessingError> {
+        let scaled = intrinsics.scaled_to(Point2D::new(
+            self.input_image_x as f64,
+            self.input_image_y as f64,
+        ))?;
+        self.pnp.reset_all();
+        self.pnp.solver_mut().set_intrinsics(&scaled)
+    }
+
+    /// Get a reference to the face processor's 3D face model.
+    pub fn face_model(&self) -> &FaceModel3D {
+        self.pnp.solver().face_model()
+    }
+
+    /// Set the face processor's 3D face model.
+    pub fn set_face_model(&mut self, face_model: FaceModel3D) -> Result<(), FacialProcessingError> {
+        self.pnp.reset_all();
+        self.pnp.solver_mut().set_face_model(face_model)
+    }
+
+    /// Get a reference to the face processor's identity gallery.
+    pub fn identity_gallery(&self) -> &IdentityGallery {
+        &self.identities
+    }
+
+    /// Get a mutable reference to the face processor's identity gallery. Identities remembered by
+    /// `identify_tracked` are kept, call `reset_tracked_identities` after removing someone.
+    pub fn identity_gallery_mut(&mut self) -> &mut IdentityGallery {
+        &mut self.identities
+    }
+
+    /// Forget the identities remembered by `identify_tracked` for every face.
+    pub fn reset_tracked_identities(&mut self) {
+        self.tracked_identities.clear();
+    }
+
+    /// Get the face processor's PnP method.
+    pub fn pnp_method(&self) -> PnPMethod {
+        self.pnp.solver().method()
+    }
+
+    /// Set the face processor's PnP method.
+    pub fn set_pnp_method(&mut self, pnp_method: PnPMethod) -> Result<(), FacialProcessingError> {
+        self.pnp.reset_all();
+        self.pnp.solver_mut().set_method(pnp_method)
+    }
 }
@@ -1,18 +1,93 @@
-use crate::{error::FacialProcessingError, utils::{face::FaceLandmark, misc::{BoundingBox, EulerAngles}}};
-use image::{ImageBuffer, Rgb};
+use crate::{error::FacialProcessingError, utils::{detection::Detection, eyes::Iris, face::FaceLandmark, frame::{Frame, RgbImage}, identity::FaceEmbedding, layout::LandmarkLayout, misc::{BoundingBox, LeftRight, SupportedProcesses}, pose::HeadPose}};
+use image::ImageBuffer;
 
+/// A face processing backend. `FaceProcessorBuilder::build` calls `init` once before the first
+/// frame, and `FaceProcessor::shutdown` calls `shutdown` after the last.
 pub trait FaceProcessorTrait {
-    fn init(&self, cpu: i16, confidence: f32) -> Result<(), FacialProcessingError>;
-    fn get_face_detections(&self, data: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<BoundingBox>;
+    /// Apply the thread count and detection confidence cut-off. `None` leaves that setting as it
+    /// is, so the `FaceProcessor` setters can call this again to change one at runtime.
+    fn init(
+        &mut self,
+        _threads: Option<i16>,
+        _confidence: Option<f32>,
+    ) -> Result<(), FacialProcessingError> {
+        Ok(())
+    }
+
+    /// Run the detector once on a black frame of this size, so allocation and any lazy setup do not
+    /// land on the first real frame.
+    fn warmup(&mut self, width: u32, height: u32) -> Result<(), FacialProcessingError> {
+        let blank: RgbImage = ImageBuffer::new(width, height);
+        self.get_face_detections(&Frame::from(blank)).map(|_| ())
+    }
+
+    /// Release anything that should not wait for the backend to be dropped.
+    fn shutdown(&mut self) -> Result<(), FacialProcessingError> {
+        Ok(())
+    }
+
+    fn get_face_detections(
+        &mut self,
+        frame: &Frame,
+    ) -> Result<Vec<Detection>, FacialProcessingError>;
+
     fn get_face_landmark(
-        &self,
-        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        &mut self,
+        frame: &Frame,
         bbox: BoundingBox,
-    ) -> FaceLandmark;
+    ) -> Result<FaceLandmark, FacialProcessingError>;
+
+    /// Landmark several faces of the same frame. Backends that can share work between the faces,
+    /// such as converting the frame once, should override this.
+    fn get_face_landmarks(
+        &mut self,
+        frame: &Frame,
+        bboxes: &[BoundingBox],
+    ) -> Result<Vec<FaceLandmark>, FacialProcessingError> {
+        bboxes
+            .iter()
+            .map(|bbox| self.get_face_landmark(frame, *bbox))
+            .collect()
+    }
+
+    /// The stages this backend runs itself. `FaceProcessor` solves pose with its own `PnPSolver`
+    /// unless `PoseEstimation` is listed.
+    fn supported_processes(&self) -> Vec<SupportedProcesses>;
+
+    /// The markup of the landmarks `get_face_landmark` returns.
+    fn landmark_layout(&self) -> LandmarkLayout;
+
+    /// Locate the iris of one eye from its landmarks. Only called if the backend lists `Eyesolation`.
+    fn get_iris(
+        &mut self,
+        _frame: &Frame,
+        _landmark: &FaceLandmark,
+        _side: LeftRight,
+    ) -> Result<Iris, FacialProcessingError> {
+        Err(FacialProcessingError::UnsupportedProcess(
+            SupportedProcesses::Eyesolation,
+        ))
+    }
+
+    /// Only called if the backend lists `PoseEstimation`.
+    fn get_pnp_forward(
+        &mut self,
+        _frame: &Frame,
+        _landmark: FaceLandmark,
+    ) -> Result<HeadPose, FacialProcessingError> {
+        Err(FacialProcessingError::UnsupportedProcess(
+            SupportedProcesses::PoseEstimation,
+        ))
+    }
 
-    fn get_pnp_forward(&self,
-        data: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-        landmark: FaceLandmark) -> EulerAngles;
-    
-        
+    /// Describe the face in `bbox` for recognition. Backends without a face encoder keep this default.
+    fn get_face_embedding(
+        &mut self,
+        _frame: &Frame,
+        _bbox: BoundingBox,
+    ) -> Result<FaceEmbedding, FacialProcessingError> {
+        Err(FacialProcessingError::UnsupportedProcess(
+            SupportedProcesses::Recognition,
+        ))
+    }
 }
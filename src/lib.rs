@@ -1,5 +1,7 @@
 #[cfg(feature = "opencl")]
 extern crate cl3;
+#[cfg(feature = "onnx")]
+extern crate tract_onnx;
 #[cfg(feature = "tf")]
 extern crate tflite;
 #[cfg(feature = "apachetvm")]
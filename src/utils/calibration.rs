@@ -0,0 +1,464 @@
+use crate::{error::FacialProcessingError, mat_init, utils::misc::Point2D};
+use cv_convert::TryFromCv;
+use image::{imageops::grayscale, GrayImage, ImageBuffer, Rgb};
+use nalgebra::Matrix3;
+use opencv::{
+    calib3d::{
+        calibrate_camera, find_chessboard_corners, CALIB_CB_ADAPTIVE_THRESH,
+        CALIB_CB_NORMALIZE_IMAGE,
+    },
+    core::{
+        Mat, Point2f, Point3f, Scalar, Size, TermCriteria, TermCriteria_Type, Vector, CV_64F,
+        CV_8UC1,
+    },
+    imgproc::corner_sub_pix,
+    prelude::*,
+};
+use std::{
+    fmt::Write as FmtWrite,
+    fs::{read_to_string, write},
+    path::Path,
+};
+
+// How far apart two aspect ratios can be and still count as the same, relative to each other.
+const ASPECT_TOLERANCE: f64 = 0.01;
+
+/// The intrinsic parameters of a camera: focal lengths, principal point and lens distortion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CameraIntrinsics {
+    camera_matrix: Matrix3<f64>,
+    distortion: Vec<f64>,
+    resolution: Point2D,
+    reprojection_error: Option<f64>,
+}
+
+impl CameraIntrinsics {
+    pub fn new(camera_matrix: Matrix3<f64>, distortion: Vec<f64>, resolution: Point2D) -> Self {
+        CameraIntrinsics {
+            camera_matrix,
+            distortion,
+            resolution,
+            reprojection_error: None,
+        }
+    }
+
+    /// An uncalibrated guess: focal length = image width, principal point at the center, no distortion.
+    pub fn from_resolution(resolution: Point2D) -> Self {
+        let focal_len = resolution.x;
+        let center = Point2D::new(resolution.x / 2_f64, resolution.y / 2_f64);
+        let camera_matrix = Matrix3::from_row_slice(&[
+            focal_len, 0.0, center.x, 0.0, focal_len, center.y, 0.0, 0.0, 1.0,
+        ]);
+        CameraIntrinsics::new(camera_matrix, vec![0.0; 4], resolution)
+    }
+
+    /// Calibrate from a set of images of a chessboard. `board_size` is the number of *inner* corners
+    /// (columns, rows), and `square_size` the length of a square in whatever unit you want out.
+    /// Images where the board cannot be found are skipped, but at least 3 must be usable.
+    pub fn calibrate_chessboard(
+        images: &[ImageBuffer<Rgb<u8>, Vec<u8>>],
+        board_size: (i32, i32),
+        square_size: f64,
+    ) -> Result<Self, FacialProcessingError> {
+        let first = match images.first() {
+            Some(img) => img,
+            None => {
+                return Err(FacialProcessingError::InitializeError(
+                    "No calibration images given!".to_string(),
+                ))
+            }
+        };
+        let resolution = Point2D::new(first.width() as f64, first.height() as f64);
+        let pattern_size = Size::new(board_size.0, board_size.1);
+
+        let mut board: Vector<Point3f> = Vector::new();
+        for row in 0..board_size.1 {
+            for col in 0..board_size.0 {
+                board.push(Point3f::new(
+                    (col as f64 * square_size) as f32,
+                    (row as f64 * square_size) as f32,
+                    0.0,
+                ));
+            }
+        }
+
+        let criteria = match TermCriteria::new(
+            TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+            30,
+            0.001,
+        ) {
+            Ok(c) => c,
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        };
+
+        let mut object_points: Vector<Vector<Point3f>> = Vector::new();
+        let mut image_points: Vector<Vector<Point2f>> = Vector::new();
+        for image in images {
+            if image.width() as f64 != resolution.x || image.height() as f64 != resolution.y {
+                return Err(FacialProcessingError::InitializeError(format!(
+                    "All calibration images must be {}x{}, got {}x{}",
+                    resolution.x,
+                    resolution.y,
+                    image.width(),
+                    image.height()
+                )));
+            }
+
+            let gray = luma_to_mat(&grayscale(image))?;
+            let mut corners: Vector<Point2f> = Vector::new();
+            let found = match find_chessboard_corners(
+                &gray,
+                pattern_size,
+                &mut corners,
+                CALIB_CB_ADAPTIVE_THRESH + CALIB_CB_NORMALIZE_IMAGE,
+            ) {
+                Ok(f) => f,
+                Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+            };
+            if !found {
+                continue;
+            }
+
+            if let Err(why) = corner_sub_pix(
+                &gray,
+                &mut corners,
+                Size::new(11, 11),
+                Size::new(-1, -1),
+                criteria,
+            ) {
+                return Err(FacialProcessingError::InternalError(why.to_string()));
+            }
+
+            object_points.push(board.clone());
+            image_points.push(corners);
+        }
+
+        if image_points.len() < 3 {
+            return Err(FacialProcessingError::InitializeError(format!(
+                "Found the chessboard in {} images, need at least 3",
+                image_points.len()
+            )));
+        }
+
+        let mut camera_matrix = mat_init!();
+        let mut distortion = mat_init!();
+        let mut rvecs: Vector<Mat> = Vector::new();
+        let mut tvecs: Vector<Mat> = Vector::new();
+        let reprojection_error = match calibrate_camera(
+            &object_points,
+            &image_points,
+            Size::new(resolution.x as i32, resolution.y as i32),
+            &mut camera_matrix,
+            &mut distortion,
+            &mut rvecs,
+            &mut tvecs,
+            0,
+            criteria,
+        ) {
+            Ok(err) => err,
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        };
+
+        let mut camera_matrix_na: Matrix3<f64> = Matrix3::zeros();
+        for row in 0..3 {
+            for col in 0..3 {
+                camera_matrix_na[(row, col)] = match camera_matrix
+                    .at_2d::<f64>(row as i32, col as i32)
+                {
+                    Ok(v) => *v,
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+                };
+            }
+        }
+
+        let mut distortion_coeffs = vec![];
+        for idx in 0..(distortion.total().unwrap_or(0) as i32) {
+            match distortion.at::<f64>(idx) {
+                Ok(v) => distortion_coeffs.push(*v),
+                Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+            }
+        }
+
+        Ok(CameraIntrinsics {
+            camera_matrix: camera_matrix_na,
+            distortion: distortion_coeffs,
+            resolution,
+            reprojection_error: Some(reprojection_error),
+        })
+    }
+
+    /// Save the intrinsics to a plain text file, readable by `load`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FacialProcessingError> {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "resolution {} {}",
+            self.resolution.x, self.resolution.y
+        );
+        let _ = write!(out, "camera_matrix");
+        for row in 0..3 {
+            for col in 0..3 {
+                let _ = write!(out, " {}", self.camera_matrix[(row, col)]);
+            }
+        }
+        let _ = writeln!(out);
+        let _ = write!(out, "distortion");
+        for coeff in &self.distortion {
+            let _ = write!(out, " {}", coeff);
+        }
+        let _ = writeln!(out);
+        if let Some(err) = self.reprojection_error {
+            let _ = writeln!(out, "reprojection_error {}", err);
+        }
+
+        match write(path, out) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(FacialProcessingError::IoError(why.to_string())),
+        }
+    }
+
+    /// Load intrinsics previously written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FacialProcessingError> {
+        let contents = match read_to_string(path) {
+            Ok(c) => c,
+            Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+        };
+
+        let mut resolution = None;
+        let mut camera_matrix = None;
+        let mut distortion = vec![];
+        let mut reprojection_error = None;
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+            let key = match fields.next() {
+                Some(k) => k,
+                None => continue,
+            };
+            let mut values = vec![];
+            for field in fields {
+                match field.parse::<f64>() {
+                    Ok(v) => values.push(v),
+                    Err(why) => {
+                        return Err(FacialProcessingError::IoError(format!(
+                            "Bad value for {}: {}",
+                            key,
+                            why.to_string()
+                        )))
+                    }
+                }
+            }
+            match (key, values.len()) {
+                ("resolution", 2) => resolution = Some(Point2D::new(values[0], values[1])),
+                ("camera_matrix", 9) => camera_matrix = Some(Matrix3::from_row_slice(&values)),
+                ("distortion", _) => distortion = values,
+                ("reprojection_error", 1) => reprojection_error = Some(values[0]),
+                (_, n) => {
+                    return Err(FacialProcessingError::IoError(format!(
+                        "Unexpected entry {} with {} values",
+                        key, n
+                    )))
+                }
+            }
+        }
+
+        match (resolution, camera_matrix) {
+            (Some(resolution), Some(camera_matrix)) => Ok(CameraIntrinsics {
+                camera_matrix,
+                distortion,
+                resolution,
+                reprojection_error,
+            }),
+            _ => Err(FacialProcessingError::IoError(
+                "Missing resolution or camera_matrix".to_string(),
+            )),
+        }
+    }
+
+    /// Get the camera matrix as an OpenCV `Mat`.
+    pub fn camera_matrix_mat(&self) -> Result<Mat, FacialProcessingError> {
+        match Mat::try_from_cv(self.camera_matrix) {
+            Ok(m) => Ok(m),
+            Err(why) => Err(FacialProcessingError::InternalError(why.to_string())),
+        }
+    }
+
+    /// Get the distortion coefficients as an OpenCV `Mat`.
+    pub fn distortion_mat(&self) -> Result<Mat, FacialProcessingError> {
+        let mut mat = match Mat::new_rows_cols_with_default(
+            self.distortion.len().max(4) as i32,
+            1,
+            CV_64F,
+            Scalar::all(0.0),
+        ) {
+            Ok(m) => m,
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        };
+        for (idx, coeff) in self.distortion.iter().enumerate() {
+            match mat.at_mut::<f64>(idx as i32) {
+                Ok(v) => *v = *coeff,
+                Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+            }
+        }
+        Ok(mat)
+    }
+
+    /// The same camera at another resolution (e.g. the webcam set to a different mode).
+    /// This assumes the sensor is scaled, not cropped, so the aspect ratio has to match.
+    pub fn scaled_to(&self, resolution: Point2D) -> Result<Self, FacialProcessingError> {
+        if resolution.x <= 0.0
+            || resolution.y <= 0.0
+            || self.resolution.x <= 0.0
+            || self.resolution.y <= 0.0
+        {
+            return Err(FacialProcessingError::InvalidIntrinsics(format!(
+                "cannot scale from {} to {}",
+                self.resolution, resolution
+            )));
+        }
+        let scale_x = resolution.x / self.resolution.x;
+        let scale_y = resolution.y / self.resolution.y;
+        // allow for rounding, e.g. 1920x1080 to 854x480
+        if (scale_x / scale_y - 1.0).abs() > ASPECT_TOLERANCE {
+            return Err(FacialProcessingError::InvalidIntrinsics(format!(
+                "calibrated at {}, which has another aspect ratio than {}. Cropped modes need their own calibration",
+                self.resolution, resolution
+            )));
+        }
+        let mut camera_matrix = self.camera_matrix;
+        camera_matrix[(0, 0)] *= scale_x;
+        camera_matrix[(0, 2)] *= scale_x;
+        camera_matrix[(1, 1)] *= scale_y;
+        camera_matrix[(1, 2)] *= scale_y;
+        Ok(CameraIntrinsics {
+            camera_matrix,
+            distortion: self.distortion.clone(),
+            resolution,
+            reprojection_error: self.reprojection_error,
+        })
+    }
+
+    /// Get the camera intrinsics' camera matrix.
+    pub fn camera_matrix(&self) -> Matrix3<f64> {
+        self.camera_matrix
+    }
+
+    /// Set the camera intrinsics' camera matrix.
+    pub fn set_camera_matrix(&mut self, camera_matrix: Matrix3<f64>) {
+        self.camera_matrix = camera_matrix;
+    }
+
+    /// Get a reference to the camera intrinsics' distortion coefficients.
+    pub fn distortion(&self) -> &Vec<f64> {
+        &self.distortion
+    }
+
+    /// Set the camera intrinsics' distortion coefficients.
+    pub fn set_distortion(&mut self, distortion: Vec<f64>) {
+        self.distortion = distortion;
+    }
+
+    /// Get a reference to the camera intrinsics' resolution.
+    pub fn resolution(&self) -> &Point2D {
+        &self.resolution
+    }
+
+    /// Get the RMS reprojection error of the calibration, if this came from one.
+    pub fn reprojection_error(&self) -> Option<f64> {
+        self.reprojection_error
+    }
+}
+
+pub(crate) fn luma_to_mat(image: &GrayImage) -> Result<Mat, FacialProcessingError> {
+    let mut mat = match Mat::new_rows_cols_with_default(
+        image.height() as i32,
+        image.width() as i32,
+        CV_8UC1,
+        Scalar::all(0.0),
+    ) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    };
+    match mat.data_bytes_mut() {
+        Ok(bytes) => bytes.copy_from_slice(image.as_raw()),
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    }
+    Ok(mat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibrated() -> CameraIntrinsics {
+        let mut intrinsics = CameraIntrinsics::new(
+            Matrix3::from_row_slice(&[1400.5, 0.0, 962.25, 0.0, 1398.75, 541.5, 0.0, 0.0, 1.0]),
+            vec![0.125, -0.25, 0.001, -0.002, 0.0625],
+            Point2D::new(1920.0, 1080.0),
+        );
+        intrinsics.reprojection_error = Some(0.3125);
+        intrinsics
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let intrinsics = calibrated();
+        let path = std::env::temp_dir().join(format!(
+            "facial_processing_intrinsics_{}.txt",
+            std::process::id()
+        ));
+        intrinsics.save(&path).unwrap();
+        let loaded = CameraIntrinsics::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.unwrap(), intrinsics);
+    }
+
+    #[test]
+    fn load_needs_resolution_and_camera_matrix() {
+        let path = std::env::temp_dir().join(format!(
+            "facial_processing_intrinsics_bad_{}.txt",
+            std::process::id()
+        ));
+        for contents in &[
+            "distortion 0 0 0 0\n",
+            "resolution 640 480\ncamera_matrix 1 0 0 0 1 0 0 0\n",
+            "resolution 640 x\ncamera_matrix 1 0 0 0 1 0 0 0 1\n",
+        ] {
+            write(&path, contents).unwrap();
+            assert!(CameraIntrinsics::load(&path).is_err());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn scaled_to_scales_focal_length_and_principal_point() {
+        let scaled = calibrated().scaled_to(Point2D::new(960.0, 540.0)).unwrap();
+        let matrix = scaled.camera_matrix();
+        assert_eq!(matrix[(0, 0)], 700.25);
+        assert_eq!(matrix[(1, 1)], 699.375);
+        assert_eq!(matrix[(0, 2)], 481.125);
+        assert_eq!(matrix[(1, 2)], 270.75);
+        assert_eq!(matrix[(2, 2)], 1.0);
+        assert_eq!(scaled.distortion(), calibrated().distortion());
+        assert_eq!(scaled.resolution(), &Point2D::new(960.0, 540.0));
+        // back up is where it started
+        assert_eq!(
+            scaled.scaled_to(Point2D::new(1920.0, 1080.0)).unwrap(),
+            calibrated()
+        );
+    }
+
+    #[test]
+    fn scaled_to_allows_rounded_resolutions() {
+        assert!(calibrated().scaled_to(Point2D::new(854.0, 480.0)).is_ok());
+    }
+
+    #[test]
+    fn scaled_to_rejects_other_aspect_ratios_and_empty_resolutions() {
+        assert!(calibrated().scaled_to(Point2D::new(640.0, 480.0)).is_err());
+        assert!(calibrated().scaled_to(Point2D::new(0.0, 0.0)).is_err());
+        assert!(calibrated()
+            .scaled_to(Point2D::new(-1920.0, -1080.0))
+            .is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::utils::misc::{BoundingBox, Point2D};
+use std::cmp::Ordering;
+
+/// A face found by a detector.
+#[derive(Copy, Clone, Debug, Default, PartialOrd, PartialEq)]
+pub struct Detection {
+    pub bbox: BoundingBox,
+    /// The detector's confidence. Detectors that do not score their boxes report 1.
+    pub score: f32,
+    /// Left eye, right eye, nose tip, left and right mouth corners, for detectors that find them.
+    pub keypoints: Option<[Point2D; 5]>,
+}
+
+impl Detection {
+    pub fn new(bbox: BoundingBox, score: f32) -> Self {
+        Detection {
+            bbox,
+            score,
+            keypoints: None,
+        }
+    }
+
+    pub fn with_keypoints(self, keypoints: [Point2D; 5]) -> Self {
+        Detection {
+            keypoints: Some(keypoints),
+            ..self
+        }
+    }
+
+    /// The detection with its box and keypoints multiplied on both axes.
+    pub fn scaled(&self, x: f64, y: f64) -> Self {
+        Detection {
+            bbox: self.bbox.scaled(x, y),
+            score: self.score,
+            keypoints: self.keypoints.map(|mut points| {
+                for pt in points.iter_mut() {
+                    *pt = Point2D::new(pt.x * x, pt.y * y);
+                }
+                points
+            }),
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        (self.bbox.x_maximum - self.bbox.x_minumum).abs()
+    }
+
+    pub fn height(&self) -> i32 {
+        (self.bbox.y_maximum - self.bbox.y_minumum).abs()
+    }
+}
+
+impl From<BoundingBox> for Detection {
+    fn from(bbox: BoundingBox) -> Self {
+        Detection::new(bbox, 1.0)
+    }
+}
+
+/// How much two boxes overlap, from 0 (not at all) to 1 (the same box).
+pub fn intersection_over_union(a: &BoundingBox, b: &BoundingBox) -> f32 {
+    let corners = |bbox: &BoundingBox| {
+        [
+            bbox.x_minumum.min(bbox.x_maximum) as f32,
+            bbox.y_minumum.min(bbox.y_maximum) as f32,
+            bbox.x_minumum.max(bbox.x_maximum) as f32,
+            bbox.y_minumum.max(bbox.y_maximum) as f32,
+        ]
+    };
+    let (a, b) = (corners(a), corners(b));
+    let overlap_w = (a[2].min(b[2]) - a[0].max(b[0])).max(0.0);
+    let overlap_h = (a[3].min(b[3]) - a[1].max(b[1])).max(0.0);
+    let overlap = overlap_w * overlap_h;
+    let union = (a[2] - a[0]) * (a[3] - a[1]) + (b[2] - b[0]) * (b[3] - b[1]) - overlap;
+    if union <= 0.0 {
+        return 0.0;
+    }
+    overlap / union
+}
+
+/// Greedy non-maximum suppression: keep detections from the highest score down, dropping any that
+/// overlap an already kept one by more than `iou_threshold`. The result is sorted by score.
+pub fn non_max_suppression(mut detections: Vec<Detection>, iou_threshold: f32) -> Vec<Detection> {
+    sort_by_score(&mut detections);
+    let mut kept: Vec<Detection> = vec![];
+    for detection in detections {
+        if kept
+            .iter()
+            .all(|k| intersection_over_union(&k.bbox, &detection.bbox) <= iou_threshold)
+        {
+            kept.push(detection);
+        }
+    }
+    kept
+}
+
+/// Highest score first.
+pub fn sort_by_score(detections: &mut [Detection]) {
+    detections.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+}
+
+/// Applied to every detection stage's output by the `FaceProcessor`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DetectionFilter {
+    /// Faces narrower or shorter than this many pixels are dropped.
+    pub min_face_size: u32,
+    /// Keep only this many of the highest scoring faces.
+    pub max_faces: Option<usize>,
+    /// Also run NMS with this IoU threshold, for detectors that report the same face more than once,
+    /// such as cascades.
+    pub nms_threshold: Option<f32>,
+}
+
+impl DetectionFilter {
+    /// Filter `detections`, highest score first.
+    pub fn apply(&self, detections: Vec<Detection>) -> Vec<Detection> {
+        let min_size = self.min_face_size as i32;
+        let mut detections: Vec<Detection> = detections
+            .into_iter()
+            .filter(|d| d.width() >= min_size && d.height() >= min_size)
+            .collect();
+        match self.nms_threshold {
+            Some(threshold) => detections = non_max_suppression(detections, threshold),
+            None => sort_by_score(&mut detections),
+        }
+        if let Some(max_faces) = self.max_faces {
+            detections.truncate(max_faces);
+        }
+        detections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detection(x: i32, y: i32, size: i32, score: f32) -> Detection {
+        Detection::new(
+            BoundingBox {
+                x_minumum: x,
+                x_maximum: x + size,
+                y_minumum: y,
+                y_maximum: y + size,
+            },
+            score,
+        )
+    }
+
+    #[test]
+    fn intersection_over_union_of_same_disjoint_and_overlapping_boxes() {
+        let a = detection(0, 0, 10, 1.0).bbox;
+        assert_eq!(intersection_over_union(&a, &a), 1.0);
+        assert_eq!(
+            intersection_over_union(&a, &detection(20, 20, 10, 1.0).bbox),
+            0.0
+        );
+        // 50 of 150 pixels
+        let shifted = detection(5, 0, 10, 1.0).bbox;
+        assert!((intersection_over_union(&a, &shifted) - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn non_max_suppression_keeps_the_best_of_overlapping_boxes() {
+        let detections = vec![
+            detection(1, 1, 10, 0.6),
+            detection(100, 100, 10, 0.7),
+            detection(0, 0, 10, 0.9),
+        ];
+        let kept = non_max_suppression(detections, 0.5);
+        assert_eq!(
+            kept,
+            vec![detection(0, 0, 10, 0.9), detection(100, 100, 10, 0.7)]
+        );
+    }
+
+    #[test]
+    fn non_max_suppression_keeps_boxes_under_the_threshold() {
+        // IoU of 1/3
+        let detections = vec![detection(0, 0, 10, 0.9), detection(5, 0, 10, 0.8)];
+        assert_eq!(non_max_suppression(detections.clone(), 0.5).len(), 2);
+        assert_eq!(non_max_suppression(detections, 0.3).len(), 1);
+    }
+
+    #[test]
+    fn filter_drops_small_faces_and_keeps_the_highest_scores() {
+        let filter = DetectionFilter {
+            min_face_size: 20,
+            max_faces: Some(2),
+            nms_threshold: None,
+        };
+        let detections = vec![
+            detection(0, 0, 30, 0.5),
+            detection(50, 0, 10, 0.99),
+            detection(100, 0, 30, 0.8),
+            detection(150, 0, 30, 0.7),
+        ];
+        assert_eq!(
+            filter.apply(detections),
+            vec![detection(100, 0, 30, 0.8), detection(150, 0, 30, 0.7)]
+        );
+    }
+
+    #[test]
+    fn filter_runs_nms_only_when_asked() {
+        let detections = vec![detection(0, 0, 30, 0.8), detection(1, 1, 30, 0.9)];
+        assert_eq!(
+            DetectionFilter::default().apply(detections.clone()).len(),
+            2
+        );
+
+        let filter = DetectionFilter {
+            nms_threshold: Some(0.5),
+            ..DetectionFilter::default()
+        };
+        assert_eq!(filter.apply(detections), vec![detection(1, 1, 30, 0.9)]);
+    }
+
+    #[test]
+    fn scaled_maps_box_and_keypoints() {
+        let keypoints = [Point2D::new(10.0, 20.0); 5];
+        let scaled = detection(10, 20, 30, 0.8)
+            .with_keypoints(keypoints)
+            .scaled(2.0, 0.5);
+        assert_eq!(
+            scaled.bbox,
+            BoundingBox {
+                x_minumum: 20,
+                x_maximum: 80,
+                y_minumum: 10,
+                y_maximum: 25,
+            }
+        );
+        assert_eq!(scaled.score, 0.8);
+        assert_eq!(scaled.keypoints, Some([Point2D::new(20.0, 10.0); 5]));
+        assert_eq!(detection(10, 20, 30, 0.8).scaled(2.0, 0.5).keypoints, None);
+    }
+}
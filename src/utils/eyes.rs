@@ -1,11 +1,18 @@
 use crate::{
+    error::FacialProcessingError,
     pt_dist, pt_mdpt,
     utils::{
         face::FaceLandmark,
         misc::{LeftRight, Point2D},
     },
 };
-use image::{ImageBuffer, Rgb};
+
+/// An iris found by an eye model, in image coordinates.
+#[derive(Copy, Clone, Debug, Default, PartialOrd, PartialEq)]
+pub struct Iris {
+    pub center: Point2D,
+    pub radius: f64,
+}
 
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
 pub struct Eye {
@@ -15,19 +22,15 @@ pub struct Eye {
     ear_aspect_ratio: f64,
 }
 impl Eye {
-    pub fn new(
-        landmarks: &FaceLandmark,
-        side: LeftRight,
-        _image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
-    ) -> Self {
-        let points = landmarks.eye_landmarks(side).to_vec();
+    pub fn new(landmarks: &FaceLandmark, side: LeftRight) -> Result<Self, FacialProcessingError> {
+        let points = landmarks.eye_landmarks(side)?;
         // calculate the ratio
         let vertical = {
-            let dist_a = pt_dist!(*points.get(1).unwrap(), *points.get(5).unwrap());
-            let dist_b = pt_dist!(*points.get(2).unwrap(), *points.get(4).unwrap());
+            let dist_a = pt_dist!(points[1], points[5]);
+            let dist_b = pt_dist!(points[2], points[4]);
             dist_a + dist_b
         };
-        let mut horizontal = pt_dist!(*points.get(0).unwrap(), *points.get(3).unwrap());
+        let mut horizontal = pt_dist!(points[0], points[3]);
         if horizontal == 0_f64 {
             horizontal = 1.0_f64;
         }
@@ -62,21 +65,14 @@ impl Eye {
         // this is hacky code but we'll just assume the iris is at the center of the eye
         // im too lazy for this
 
-        let center_pt = pt_mdpt![
-            *points.get(0).unwrap(),
-            *points.get(1).unwrap(),
-            *points.get(2).unwrap(),
-            *points.get(3).unwrap(),
-            *points.get(4).unwrap(),
-            *points.get(5).unwrap()
-        ];
+        let center_pt = pt_mdpt![points[0], points[1], points[2], points[3], points[4], points[5]];
 
-        Eye {
-            points: landmarks.eye_landmarks(side),
+        Ok(Eye {
+            points,
             side,
             center_pt,
             ear_aspect_ratio,
-        }
+        })
     }
 
     pub fn eye_ear_ratio(&self) -> f64 {
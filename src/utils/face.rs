@@ -1,21 +1,82 @@
-use crate::utils::misc::{BoundingBox, LeftRight, Point2D};
+use crate::{
+    error::FacialProcessingError,
+    utils::{
+        layout::LandmarkLayout,
+        misc::{BoundingBox, LeftRight, Point2D},
+    },
+};
 #[cfg(feature = "dlib")]
 use dlib_face_recognition::Point;
-use opencv::core::{Point2d, ToInputArray, Vector, _InputArray};
+use opencv::core::{_InputArray, Point2d, Point2f, ToInputArray, Vector};
 
 #[derive(Clone, Debug, Default, PartialOrd, PartialEq)]
 pub struct FaceLandmark {
     all: Vec<Point2D>,
     bbox: BoundingBox,
+    layout: LandmarkLayout,
 }
 impl FaceLandmark {
+    /// Fails if `all` does not have exactly as many points as `layout`, or any point is not finite.
+    pub fn new(
+        bbox: BoundingBox,
+        all: Vec<Point2D>,
+        layout: LandmarkLayout,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
+        let landmark = FaceLandmark { all, bbox, layout };
+        match landmark.validate() {
+            Ok(_) => Ok(landmark),
+            Err(why) => Err(why),
+        }
+    }
+
     #[cfg(feature = "dlib")]
-    pub fn from_dlib(bbox: BoundingBox, landmarks: Vec<Point>) -> FaceLandmark {
+    pub fn from_dlib(
+        bbox: BoundingBox,
+        landmarks: Vec<Point>,
+        layout: LandmarkLayout,
+    ) -> Result<FaceLandmark, FacialProcessingError> {
         let mut all = vec![];
         for pt in landmarks {
             all.push(Point2D::from(pt))
         }
-        FaceLandmark { all, bbox }
+        FaceLandmark::new(bbox, all, layout)
+    }
+
+    /// Check that the points match the layout. `new` already does this, but a `Default` landmark
+    /// (which has no points) will fail it.
+    pub fn validate(&self) -> Result<(), FacialProcessingError> {
+        if self.all.len() != self.layout.point_count() {
+            return Err(FacialProcessingError::InvalidLandmark(format!(
+                "{} landmarks need {} points, got {}",
+                self.layout.name(),
+                self.layout.point_count(),
+                self.all.len()
+            )));
+        }
+        if let Some(index) = self
+            .all
+            .iter()
+            .position(|pt| !pt.x().is_finite() || !pt.y().is_finite())
+        {
+            return Err(FacialProcessingError::InvalidLandmark(format!(
+                "Point {} is {}",
+                index, self.all[index]
+            )));
+        }
+        Ok(())
+    }
+
+    /// The landmark with every point and its box multiplied on both axes.
+    pub fn scaled(&self, x: f64, y: f64) -> FaceLandmark {
+        FaceLandmark {
+            all: self
+                .all
+                .iter()
+                .map(|pt| Point2D::new(pt.x * x, pt.y * y))
+                .collect(),
+            bbox: self.bbox.scaled(x, y),
+            layout: self.layout.clone(),
+        }
     }
 
     pub fn landmarks(&self) -> Vec<Point2D> {
@@ -26,122 +87,80 @@ impl FaceLandmark {
         self.bbox
     }
 
-    pub fn eye_landmarks(&self, side: LeftRight) -> [Point2D; 6] {
-        match side {
-            LeftRight::Left => [
-                *self.all.get(36).unwrap(),
-                *self.all.get(37).unwrap(),
-                *self.all.get(38).unwrap(),
-                *self.all.get(39).unwrap(),
-                *self.all.get(40).unwrap(),
-                *self.all.get(41).unwrap(),
-            ],
-            LeftRight::Right => [
-                *self.all.get(42).unwrap(),
-                *self.all.get(43).unwrap(),
-                *self.all.get(44).unwrap(),
-                *self.all.get(45).unwrap(),
-                *self.all.get(46).unwrap(),
-                *self.all.get(47).unwrap(),
-            ],
+    pub fn layout(&self) -> &LandmarkLayout {
+        &self.layout
+    }
+
+    /// Get a single point, failing if the landmark does not have it.
+    pub fn point(&self, index: usize) -> Result<Point2D, FacialProcessingError> {
+        match self.all.get(index) {
+            Some(pt) => Ok(*pt),
+            None => Err(FacialProcessingError::MissingLandmark {
+                index,
+                available: self.all.len(),
+            }),
         }
     }
 
-    pub fn eyebrow_landmarks(&self, side: LeftRight) -> [Point2D; 5] {
-        match side {
-            LeftRight::Left => [
-                *self.all.get(17).unwrap(),
-                *self.all.get(18).unwrap(),
-                *self.all.get(19).unwrap(),
-                *self.all.get(20).unwrap(),
-                *self.all.get(21).unwrap(),
-            ],
-            LeftRight::Right => [
-                *self.all.get(22).unwrap(),
-                *self.all.get(23).unwrap(),
-                *self.all.get(24).unwrap(),
-                *self.all.get(25).unwrap(),
-                *self.all.get(26).unwrap(),
-            ],
+    fn gather<const N: usize>(
+        &self,
+        region: &'static str,
+        indices: Option<[usize; N]>,
+    ) -> Result<[Point2D; N], FacialProcessingError> {
+        let indices = match indices {
+            Some(i) => i,
+            None => {
+                return Err(FacialProcessingError::UnsupportedRegion {
+                    region,
+                    layout: self.layout.name().to_string(),
+                })
+            }
+        };
+        let mut points = [Point2D::default(); N];
+        for (point, index) in points.iter_mut().zip(indices.iter()) {
+            *point = self.point(*index)?;
         }
+        Ok(points)
+    }
+
+    pub fn eye_landmarks(&self, side: LeftRight) -> Result<[Point2D; 6], FacialProcessingError> {
+        self.gather("eyes", self.layout.eye(side))
+    }
+
+    pub fn eyebrow_landmarks(
+        &self,
+        side: LeftRight,
+    ) -> Result<[Point2D; 5], FacialProcessingError> {
+        self.gather("eyebrows", self.layout.eyebrow(side))
+    }
+
+    pub fn mouth_landmarks(&self) -> Result<([Point2D; 8], [Point2D; 12]), FacialProcessingError> {
+        let mouth_inner_parties = self.gather("inner mouth", self.layout.mouth_inner())?;
+        let mouth_outer_parties = self.gather("outer mouth", self.layout.mouth_outer())?;
+        Ok((mouth_inner_parties, mouth_outer_parties))
+    }
+
+    pub fn nose_landmarks(&self) -> Result<([Point2D; 4], [Point2D; 5]), FacialProcessingError> {
+        let nose_line = self.gather("nose line", self.layout.nose_line())?;
+        let nose_bottom = self.gather("nose bottom", self.layout.nose_bottom())?;
+        Ok((nose_line, nose_bottom))
+    }
+
+    pub fn chin_landmarks(&self) -> Result<[Point2D; 17], FacialProcessingError> {
+        self.gather("jaw", self.layout.jaw())
+    }
+
+    pub fn pnp_landmarks(&self) -> Result<[Point2D; 6], FacialProcessingError> {
+        self.gather("PnP points", self.layout.pnp())
     }
 
-    pub fn mouth_landmarks(&self) -> ([Point2D; 8], [Point2D; 12]) {
-        let mouth_inner_parties = [
-            *self.all.get(60).unwrap(),
-            *self.all.get(61).unwrap(),
-            *self.all.get(62).unwrap(),
-            *self.all.get(63).unwrap(),
-            *self.all.get(64).unwrap(),
-            *self.all.get(65).unwrap(),
-            *self.all.get(66).unwrap(),
-            *self.all.get(67).unwrap(),
-        ];
-        let mouth_outer_parties = [
-            *self.all.get(48).unwrap(),
-            *self.all.get(49).unwrap(),
-            *self.all.get(50).unwrap(),
-            *self.all.get(51).unwrap(),
-            *self.all.get(52).unwrap(),
-            *self.all.get(53).unwrap(),
-            *self.all.get(54).unwrap(),
-            *self.all.get(55).unwrap(),
-            *self.all.get(56).unwrap(),
-            *self.all.get(57).unwrap(),
-            *self.all.get(58).unwrap(),
-            *self.all.get(59).unwrap(),
-        ];
-        (mouth_inner_parties, mouth_outer_parties)
-    }
-
-    pub fn nose_landmarks(&self) -> ([Point2D; 4], [Point2D; 5]) {
-        let nose_line = [
-            *self.all.get(29).unwrap(),
-            *self.all.get(30).unwrap(),
-            *self.all.get(31).unwrap(),
-            *self.all.get(32).unwrap(),
-        ];
-        let nose_bottom = [
-            *self.all.get(31).unwrap(),
-            *self.all.get(32).unwrap(),
-            *self.all.get(33).unwrap(),
-            *self.all.get(34).unwrap(),
-            *self.all.get(35).unwrap(),
-        ];
-        (nose_line, nose_bottom)
-    }
-
-    pub fn chin_landmarks(&self) -> [Point2D; 17] {
-        [
-            *self.all.get(0).unwrap(),
-            *self.all.get(1).unwrap(),
-            *self.all.get(2).unwrap(),
-            *self.all.get(3).unwrap(),
-            *self.all.get(4).unwrap(),
-            *self.all.get(5).unwrap(),
-            *self.all.get(6).unwrap(),
-            *self.all.get(7).unwrap(),
-            *self.all.get(8).unwrap(),
-            *self.all.get(9).unwrap(),
-            *self.all.get(10).unwrap(),
-            *self.all.get(11).unwrap(),
-            *self.all.get(12).unwrap(),
-            *self.all.get(13).unwrap(),
-            *self.all.get(14).unwrap(),
-            *self.all.get(15).unwrap(),
-            *self.all.get(16).unwrap(),
-        ]
-    }
-
-    pub fn pnp_landmarks(&self) -> [Point2D; 6] {
-        [
-            *self.all.get(30).unwrap(),
-            *self.all.get(8).unwrap(),
-            *self.all.get(36).unwrap(),
-            *self.all.get(45).unwrap(),
-            *self.all.get(48).unwrap(),
-            *self.all.get(54).unwrap(),
-        ]
+    pub fn eye_corners(&self, side: LeftRight) -> Result<[Point2D; 2], FacialProcessingError> {
+        self.gather("eye corners", self.layout.eye_corners(side))
+    }
+
+    pub fn nose_tip(&self) -> Result<Point2D, FacialProcessingError> {
+        let [tip] = self.gather("nose tip", self.layout.nose_tip().map(|index| [index]))?;
+        Ok(tip)
     }
 }
 
@@ -154,12 +173,29 @@ impl IntoIterator for FaceLandmark {
     }
 }
 
-impl ToInputArray for FaceLandmark {
-    fn input_array(&self) -> opencv::Result<_InputArray> {
+impl From<&FaceLandmark> for Vector<Point2d> {
+    fn from(landmark: &FaceLandmark) -> Self {
         let mut vector_pt: Vector<Point2d> = Vector::new();
-        for point in &self.all {
+        for point in &landmark.all {
             vector_pt.push(Point2D::into(*point))
         }
-        vector_pt.input_array()
+        vector_pt
+    }
+}
+
+/// Single precision, as `Facemark` and most drawing and tracking functions take.
+impl From<&FaceLandmark> for Vector<Point2f> {
+    fn from(landmark: &FaceLandmark) -> Self {
+        let mut vector_pt: Vector<Point2f> = Vector::new();
+        for point in &landmark.all {
+            vector_pt.push(Point2D::into(*point))
+        }
+        vector_pt
+    }
+}
+
+impl ToInputArray for FaceLandmark {
+    fn input_array(&self) -> opencv::Result<_InputArray> {
+        Vector::<Point2d>::from(self).input_array()
     }
 }
@@ -0,0 +1,564 @@
+use crate::{
+    error::FacialProcessingError,
+    utils::{
+        face::FaceLandmark,
+        layout::LandmarkLayout,
+        misc::{Point2D, Point3D},
+    },
+};
+use nalgebra::{Matrix3, Vector3};
+use opencv::core::{Point2d, Point3d, Vector};
+use std::{
+    cmp::Ordering,
+    fmt::Write as FmtWrite,
+    fs::{read_to_string, write},
+    path::Path,
+};
+
+// Variance off the best fitting plane, relative to the largest variance along it, that still
+// counts as planar.
+const PLANAR_TOLERANCE: f64 = 1e-6;
+
+// The 68 point iBUG mean face, in (approximately) millimetres. x grows to the image's right, y up and
+// z out of the face, like the other models.
+const IBUG68_MEAN_FACE: [[f64; 3]; 68] = [
+    // Jaw, from the image's left
+    [-73.393523, 29.801432, -47.667532],
+    [-72.775014, 10.949766, -45.909403],
+    [-70.533638, -7.929818, -44.842580],
+    [-66.850058, -26.074280, -43.141114],
+    [-59.790187, -42.564390, -38.635298],
+    [-48.368973, -56.481080, -30.750622],
+    [-34.121101, -67.246992, -18.456453],
+    [-17.875411, -75.056892, -3.609035],
+    [0.098749, -77.061286, 0.881698],
+    [17.477031, -74.758448, -5.181201],
+    [32.648966, -66.929021, -19.176563],
+    [46.372358, -56.311389, -30.770570],
+    [57.343480, -42.419126, -37.628629],
+    [64.388482, -25.455880, -40.886309],
+    [68.212038, -6.990805, -42.281449],
+    [70.486405, 11.666193, -44.142567],
+    [71.375822, 30.365191, -47.140426],
+    // Left brow
+    [-61.119406, 49.361602, -14.254422],
+    [-51.287588, 58.769795, -7.268147],
+    [-37.804800, 61.996155, -0.442051],
+    [-24.022754, 61.033399, 6.606501],
+    [-11.635713, 56.686759, 11.967398],
+    // Right brow
+    [12.056636, 57.391033, 12.051204],
+    [25.106256, 61.902186, 7.315098],
+    [38.338588, 62.777713, 1.022953],
+    [51.191007, 59.302347, -5.349435],
+    [60.053851, 50.190255, -11.615746],
+    // Nose bridge, top down
+    [0.653940, 42.193790, 13.380835],
+    [0.804809, 30.993721, 21.150853],
+    [0.992204, 19.944596, 29.284036],
+    [1.226783, 8.414541, 36.948060],
+    // Nose bottom
+    [-14.772472, -2.598255, 19.695013],
+    [-7.180239, -4.751589, 22.284869],
+    [0.555920, -6.562900, 23.480316],
+    [8.272499, -4.661005, 22.285734],
+    [15.214351, -2.643046, 19.753824],
+    // Left eye
+    [-46.047290, 37.471411, -7.212232],
+    [-37.674688, 42.730510, -1.815669],
+    [-27.883856, 42.711517, -1.233656],
+    [-19.648268, 36.754742, 0.432104],
+    [-28.272965, 35.134493, 2.059778],
+    [-38.082418, 34.919043, -1.032302],
+    // Right eye
+    [19.265868, 37.032306, 0.417003],
+    [27.894191, 43.342445, -1.006838],
+    [37.437529, 43.110822, -1.144431],
+    [45.170805, 38.086515, -7.183578],
+    [38.196454, 35.532024, -2.229919],
+    [28.764989, 35.484289, -2.058099],
+    // Outer lips
+    [-28.916267, -28.612716, 10.524883],
+    [-17.533194, -22.172187, 5.719366],
+    [-6.684590, -19.029051, 6.045436],
+    [0.381001, -20.721118, 7.154453],
+    [8.375443, -19.035460, 6.041669],
+    [18.876618, -22.394109, 5.801233],
+    [28.794412, -28.079924, 10.651770],
+    [19.057574, -36.298248, 11.126233],
+    [8.956375, -39.634575, 12.016064],
+    [0.381549, -40.395647, 12.001101],
+    [-7.428895, -39.836405, 11.906706],
+    [-18.160634, -36.677899, 11.065201],
+    // Inner lips
+    [-24.377490, -28.677771, 10.556001],
+    [-6.897633, -25.475976, 8.713532],
+    [0.340663, -26.014269, 9.036484],
+    [8.444722, -25.326198, 8.692521],
+    [24.474473, -28.323008, 10.586101],
+    [8.449166, -30.596216, 14.196069],
+    [0.205322, -31.408738, 14.233911],
+    [-7.198266, -30.884654, 14.265911],
+];
+
+/// A 3D reference face used for PnP. Each point in `points` corresponds to the landmark at the same
+/// position in `landmark_indices`, which index into landmarks of `layout`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaceModel3D {
+    points: Vec<Point3D>,
+    landmark_indices: Vec<usize>,
+    layout: LandmarkLayout,
+}
+
+impl FaceModel3D {
+    pub fn new(
+        points: Vec<Point3D>,
+        landmark_indices: Vec<usize>,
+        layout: LandmarkLayout,
+    ) -> Result<Self, FacialProcessingError> {
+        if points.len() != landmark_indices.len() {
+            return Err(FacialProcessingError::InitializeError(format!(
+                "3D model has {} points but {} landmark indices!",
+                points.len(),
+                landmark_indices.len()
+            )));
+        }
+        if points.len() < 4 {
+            return Err(FacialProcessingError::InitializeError(format!(
+                "3D model needs at least 4 points, got {}!",
+                points.len()
+            )));
+        }
+        if let Some(index) = landmark_indices
+            .iter()
+            .find(|index| **index >= layout.point_count())
+        {
+            return Err(FacialProcessingError::InitializeError(format!(
+                "Landmark index {} is out of range for a {} point layout!",
+                index,
+                layout.point_count()
+            )));
+        }
+        Ok(FaceModel3D {
+            points,
+            landmark_indices,
+            layout,
+        })
+    }
+
+    /// The original 6 point model (nose tip, chin, outer eye corners, mouth corners), in arbitrary units.
+    pub fn six_point() -> Self {
+        FaceModel3D {
+            points: FaceModel3D::six_point_coordinates(),
+            landmark_indices: vec![30, 8, 36, 45, 48, 54],
+            layout: LandmarkLayout::Ibug68,
+        }
+    }
+
+    /// The 6 point model mapped onto `layout`'s PnP points, or `None` if the layout has none.
+    pub fn six_point_for(layout: &LandmarkLayout) -> Option<Self> {
+        let indices = layout.pnp()?;
+        Some(FaceModel3D {
+            points: FaceModel3D::six_point_coordinates(),
+            landmark_indices: indices.to_vec(),
+            layout: layout.clone(),
+        })
+    }
+
+    /// A model for dlib's 5 point markup (the eye corners and the bottom of the nose), with the
+    /// eye corners from `ibug68_sparse14`. The nose point is estimated, as that model has no nose
+    /// bottom.
+    pub fn five_point() -> Self {
+        FaceModel3D {
+            points: vec![
+                Point3D::new(53.11432, 54.85328, 39.87654), // Right eye right corner
+                Point3D::new(17.89930, 53.93625, 44.13414), // Right eye left corner
+                Point3D::new(-53.11432, 54.85328, 39.87654), // Left eye left corner
+                Point3D::new(-17.89930, 53.93625, 44.13414), // Left eye right corner
+                Point3D::new(0.0, 11.5, 64.0),              // Nose bottom
+            ],
+            landmark_indices: vec![0, 1, 2, 3, 4],
+            layout: LandmarkLayout::FivePoint,
+        }
+    }
+
+    /// The model to use for `layout` when the configured one does not match it: `six_point_for`
+    /// if the layout has the points, `five_point` for the 5 point layout.
+    pub fn fallback_for(layout: &LandmarkLayout) -> Option<Self> {
+        match layout {
+            LandmarkLayout::FivePoint => Some(FaceModel3D::five_point()),
+            _ => FaceModel3D::six_point_for(layout),
+        }
+    }
+
+    fn six_point_coordinates() -> Vec<Point3D> {
+        vec![
+            Point3D::new(0.0, 0.0, 0.0),          // Nose Tip
+            Point3D::new(0.0, -330.0, -65.0),     // Chin
+            Point3D::new(-225.0, 170.0, -135.0),  // Left corner left eye
+            Point3D::new(225.0, 170.0, -135.0),   // Right corner right eye
+            Point3D::new(-150.0, -150.0, -125.0), // Mouth Corner left
+            Point3D::new(150.0, -150.0, -125.0),  // Mouth Corner right
+        ]
+    }
+
+    /// A mean face for 14 of the iBUG 68 points (brows, eye corners, nose, mouth and chin), in
+    /// (approximately) millimetres.
+    /// Adapted from https://github.com/lincolnhard/head-pose-estimation, with x mirrored to match `six_point`.
+    pub fn ibug68_sparse14() -> Self {
+        FaceModel3D {
+            points: vec![
+                Point3D::new(-68.25897, 67.60612, 44.02142), // Left brow left corner
+                Point3D::new(-13.30353, 71.22144, 69.03745), // Left brow right corner
+                Point3D::new(13.30353, 71.22144, 69.03745),  // Right brow left corner
+                Point3D::new(68.25897, 67.60612, 44.02142),  // Right brow right corner
+                Point3D::new(-53.11432, 54.85328, 39.87654), // Left eye left corner
+                Point3D::new(-17.89930, 53.93625, 44.13414), // Left eye right corner
+                Point3D::new(17.89930, 53.93625, 44.13414),  // Right eye left corner
+                Point3D::new(53.11432, 54.85328, 39.87654),  // Right eye right corner
+                Point3D::new(-20.05628, 14.09845, 61.65652), // Nose left corner
+                Point3D::new(20.05628, 14.09845, 61.65652),  // Nose right corner
+                Point3D::new(-27.74015, -20.80775, 50.48531), // Mouth left corner
+                Point3D::new(27.74015, -20.80775, 50.48531), // Mouth right corner
+                Point3D::new(0.0, -31.16408, 60.97667),      // Mouth bottom
+                Point3D::new(0.0, -74.15691, 40.70434),      // Chin
+            ],
+            landmark_indices: vec![17, 21, 22, 26, 36, 39, 42, 45, 31, 35, 48, 54, 57, 8],
+            layout: LandmarkLayout::Ibug68,
+        }
+    }
+
+    /// The mean face for all 68 iBUG points, in (approximately) millimetres. The default model.
+    /// Adapted from https://github.com/yinguobing/head-pose-estimation, with y flipped to point up
+    /// like `six_point`.
+    pub fn ibug68() -> Self {
+        FaceModel3D {
+            points: IBUG68_MEAN_FACE
+                .iter()
+                .map(|pt| Point3D::new(pt[0], pt[1], pt[2]))
+                .collect(),
+            landmark_indices: (0..IBUG68_MEAN_FACE.len()).collect(),
+            layout: LandmarkLayout::Ibug68,
+        }
+    }
+
+    /// Read a model from a text file. Each non-empty line is `index x y z`, and `#` starts a comment.
+    /// The indices are into `layout`.
+    pub fn from_file<P: AsRef<Path>>(
+        path: P,
+        layout: LandmarkLayout,
+    ) -> Result<Self, FacialProcessingError> {
+        let contents = match read_to_string(path) {
+            Ok(c) => c,
+            Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+        };
+
+        let mut points = vec![];
+        let mut landmark_indices = vec![];
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            if fields.len() != 4 {
+                return Err(FacialProcessingError::IoError(format!(
+                    "Line {}: expected `index x y z`, got {} fields",
+                    line_no + 1,
+                    fields.len()
+                )));
+            }
+            let index = match fields[0].parse::<usize>() {
+                Ok(i) => i,
+                Err(why) => {
+                    return Err(FacialProcessingError::IoError(format!(
+                        "Line {}: {}",
+                        line_no + 1,
+                        why.to_string()
+                    )))
+                }
+            };
+            let mut coords = [0_f64; 3];
+            for (coord, field) in coords.iter_mut().zip(&fields[1..]) {
+                *coord = match field.parse::<f64>() {
+                    Ok(c) => c,
+                    Err(why) => {
+                        return Err(FacialProcessingError::IoError(format!(
+                            "Line {}: {}",
+                            line_no + 1,
+                            why.to_string()
+                        )))
+                    }
+                };
+            }
+            landmark_indices.push(index);
+            points.push(Point3D::new(coords[0], coords[1], coords[2]));
+        }
+
+        FaceModel3D::new(points, landmark_indices, layout)
+    }
+
+    /// Write the model in the format `from_file` reads.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FacialProcessingError> {
+        let mut out = String::new();
+        let _ = writeln!(out, "# index x y z, for {} landmarks", self.layout.name());
+        for (point, index) in self.points.iter().zip(&self.landmark_indices) {
+            let _ = writeln!(out, "{} {} {} {}", index, point.x, point.y, point.z);
+        }
+
+        match write(path, out) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(FacialProcessingError::IoError(why.to_string())),
+        }
+    }
+
+    /// Pair every model point with its landmark. Also returns the landmark index of each pair.
+    /// Points the landmark does not have are skipped, it only fails if fewer than `min_points`
+    /// pairs are left.
+    pub fn correspondences(
+        &self,
+        landmark: &FaceLandmark,
+        min_points: usize,
+    ) -> Result<(Vector<Point3d>, Vector<Point2d>, Vec<usize>), FacialProcessingError> {
+        let mut model_points: Vector<Point3d> = Vector::new();
+        let mut image_points: Vector<Point2d> = Vector::new();
+        let mut landmark_indices = vec![];
+        for (point, index) in self.points.iter().zip(&self.landmark_indices) {
+            let pt = match landmark.point(*index) {
+                Ok(pt) => pt,
+                Err(FacialProcessingError::MissingLandmark { .. }) => continue,
+                Err(why) => return Err(why),
+            };
+            model_points.push(Point3D::into(*point));
+            image_points.push(Point2D::into(pt));
+            landmark_indices.push(*index);
+        }
+        if landmark_indices.len() < min_points {
+            return Err(FacialProcessingError::InvalidLandmark(format!(
+                "only {} of the face model's {} points are in the landmark, PnP needs {}",
+                landmark_indices.len(),
+                self.points.len(),
+                min_points
+            )));
+        }
+        Ok((model_points, image_points, landmark_indices))
+    }
+
+    /// Whether all points lie on one plane, as `PnPMethod::IPPE` needs.
+    pub fn is_planar(&self) -> bool {
+        if self.points.len() < 4 {
+            return true;
+        }
+        let points: Vec<Vector3<f64>> = self
+            .points
+            .iter()
+            .map(|pt| Vector3::new(pt.x, pt.y, pt.z))
+            .collect();
+        let (_, spread, _) = best_fit_plane(&points);
+        // the spread off the best fitting plane, against the spread along it
+        spread.min() <= spread.max() * PLANAR_TOLERANCE
+    }
+
+    /// Get a reference to the face model's points.
+    pub fn points(&self) -> &Vec<Point3D> {
+        &self.points
+    }
+
+    /// Get a reference to the face model's landmark indices.
+    pub fn landmark_indices(&self) -> &Vec<usize> {
+        &self.landmark_indices
+    }
+
+    /// Get a reference to the layout the landmark indices are for.
+    pub fn layout(&self) -> &LandmarkLayout {
+        &self.layout
+    }
+}
+
+/// The half of `model_points` (but at least 4) closest to their best fitting plane, flattened onto
+/// that plane, with their `image_points`. This lets `IPPE` solve a model that is not planar.
+pub(crate) fn planar_subset(
+    model_points: &Vector<Point3d>,
+    image_points: &Vector<Point2d>,
+) -> (Vector<Point3d>, Vector<Point2d>) {
+    let points: Vec<Vector3<f64>> = model_points
+        .iter()
+        .map(|pt| Vector3::new(pt.x, pt.y, pt.z))
+        .collect();
+    let (centroid, spread, axes) = best_fit_plane(&points);
+    let normal: Vector3<f64> = axes.column(spread.imin()).into_owned();
+
+    let mut by_distance: Vec<(usize, f64)> = points
+        .iter()
+        .map(|pt| (pt - centroid).dot(&normal))
+        .enumerate()
+        .collect();
+    by_distance.sort_by(|a, b| a.1.abs().partial_cmp(&b.1.abs()).unwrap_or(Ordering::Equal));
+
+    let keep = (points.len() / 2).max(4);
+    let mut subset_model = Vector::new();
+    let mut subset_image = Vector::new();
+    for (idx, distance) in by_distance.into_iter().take(keep) {
+        if let Ok(image_point) = image_points.get(idx) {
+            let flat = points[idx] - normal * distance;
+            subset_model.push(Point3d::new(flat.x, flat.y, flat.z));
+            subset_image.push(image_point);
+        }
+    }
+    (subset_model, subset_image)
+}
+
+// The centroid of `points`, and the eigenvalues and eigenvectors (as columns) of their covariance.
+// The eigenvector with the smallest eigenvalue is the normal of the best fitting plane.
+fn best_fit_plane(points: &[Vector3<f64>]) -> (Vector3<f64>, Vector3<f64>, Matrix3<f64>) {
+    let centroid =
+        points.iter().fold(Vector3::zeros(), |sum, pt| sum + pt) / points.len().max(1) as f64;
+    let mut covariance = Matrix3::zeros();
+    for pt in points {
+        let offset = pt - centroid;
+        covariance += offset * offset.transpose();
+    }
+    let eigen = covariance.symmetric_eigen();
+    (centroid, eigen.eigenvalues, eigen.eigenvectors)
+}
+
+impl Default for FaceModel3D {
+    fn default() -> Self {
+        FaceModel3D::ibug68()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_checks_sizes_and_indices() {
+        let points = vec![Point3D::new(0.0, 0.0, 0.0); 4];
+        assert!(FaceModel3D::new(points.clone(), vec![0, 1, 2, 3], LandmarkLayout::Ibug68).is_ok());
+        assert!(FaceModel3D::new(points.clone(), vec![0, 1, 2], LandmarkLayout::Ibug68).is_err());
+        assert!(
+            FaceModel3D::new(points[..3].to_vec(), vec![0, 1, 2], LandmarkLayout::Ibug68).is_err()
+        );
+        assert!(FaceModel3D::new(points, vec![0, 1, 2, 5], LandmarkLayout::FivePoint).is_err());
+    }
+
+    #[test]
+    fn built_in_models_are_valid() {
+        for model in &[
+            FaceModel3D::ibug68(),
+            FaceModel3D::ibug68_sparse14(),
+            FaceModel3D::six_point(),
+            FaceModel3D::five_point(),
+        ] {
+            assert!(FaceModel3D::new(
+                model.points().clone(),
+                model.landmark_indices().clone(),
+                model.layout().clone()
+            )
+            .is_ok());
+            assert!(!model.is_planar());
+        }
+        assert_eq!(FaceModel3D::default().points().len(), 68);
+    }
+
+    #[test]
+    fn from_file_skips_comments_and_blank_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "facial_processing_model_parse_{}.txt",
+            std::process::id()
+        ));
+        write(
+            &path,
+            "# a square\n\n0 -1 1 0\n1 1 1 0 # right\n  2 1 -1 0\n3 -1 -1 0.5\n",
+        )
+        .unwrap();
+        let model = FaceModel3D::from_file(&path, LandmarkLayout::FivePoint);
+        let _ = std::fs::remove_file(&path);
+
+        let model = model.unwrap();
+        assert_eq!(model.landmark_indices(), &vec![0, 1, 2, 3]);
+        assert_eq!(model.points()[1], Point3D::new(1.0, 1.0, 0.0));
+        assert_eq!(model.points()[3], Point3D::new(-1.0, -1.0, 0.5));
+    }
+
+    #[test]
+    fn from_file_rejects_bad_lines() {
+        let path = std::env::temp_dir().join(format!(
+            "facial_processing_model_bad_{}.txt",
+            std::process::id()
+        ));
+        for contents in &[
+            "0 1 2\n1 1 1 1\n2 2 2 2\n3 3 3 3\n",
+            "0 1 2 x\n1 1 1 1\n2 2 2 2\n3 3 3 3\n",
+            "-1 1 2 3\n1 1 1 1\n2 2 2 2\n3 3 3 3\n",
+            "0 0 0 0\n1 1 1 1\n",
+        ] {
+            write(&path, contents).unwrap();
+            assert!(FaceModel3D::from_file(&path, LandmarkLayout::Ibug68).is_err());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn save_from_file_round_trip() {
+        let model = FaceModel3D::ibug68();
+        let path = std::env::temp_dir().join(format!(
+            "facial_processing_model_{}.txt",
+            std::process::id()
+        ));
+        model.save(&path).unwrap();
+        let loaded = FaceModel3D::from_file(&path, LandmarkLayout::Ibug68);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.unwrap(), model);
+    }
+
+    #[test]
+    fn planar_subset_keeps_points_nearest_the_plane() {
+        let mut model_points: Vector<Point3d> = Vector::new();
+        let mut image_points: Vector<Point2d> = Vector::new();
+        for (idx, (x, y)) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .iter()
+            .enumerate()
+        {
+            model_points.push(Point3d::new(*x * 50.0, *y * 50.0, 0.0));
+            image_points.push(Point2d::new(idx as f64, 0.0));
+        }
+        // Far off the plane, so it is left out.
+        model_points.push(Point3d::new(0.0, 0.0, 40.0));
+        image_points.push(Point2d::new(4.0, 0.0));
+
+        let (subset_model, subset_image) = planar_subset(&model_points, &image_points);
+        assert_eq!(subset_model.len(), 4);
+        assert_eq!(subset_image.len(), 4);
+        for (model, image) in subset_model.iter().zip(subset_image.iter()) {
+            assert!(image.x < 4.0);
+            let original = model_points.get(image.x as usize).unwrap();
+            assert!((model.x - original.x).abs() < 1e-9);
+            assert!((model.y - original.y).abs() < 1e-9);
+        }
+        // Flattened onto the plane, which sits at the centroid's depth.
+        let depth = subset_model.get(0).unwrap().z;
+        assert!(subset_model.iter().all(|pt| (pt.z - depth).abs() < 1e-9));
+    }
+
+    #[test]
+    fn planar_models() {
+        let square = FaceModel3D::new(
+            vec![
+                Point3D::new(-1.0, 1.0, 2.0),
+                Point3D::new(1.0, 1.0, 2.0),
+                Point3D::new(1.0, -1.0, 2.0),
+                Point3D::new(-1.0, -1.0, 2.0),
+            ],
+            vec![0, 1, 2, 3],
+            LandmarkLayout::FivePoint,
+        )
+        .unwrap();
+        assert!(square.is_planar());
+    }
+}
@@ -0,0 +1,853 @@
+use crate::{error::FacialProcessingError, mat_init};
+use image::{
+    imageops::grayscale, Bgr, Bgra, DynamicImage, GenericImageView, GrayImage, ImageBuffer, Luma,
+    Pixel, Rgb, Rgba,
+};
+use opencv::{
+    core::{Mat, CV_8UC1, CV_8UC3, CV_8UC4},
+    prelude::*,
+};
+use std::{borrow::Cow, ops::Deref, sync::OnceLock};
+
+pub type RgbImage = ImageBuffer<Rgb<u8>, Vec<u8>>;
+
+/// The layout of one pixel in raw 8 bit frame data.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PixelFormat {
+    Rgb,
+    Rgba,
+    Bgr,
+    Bgra,
+    Gray,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb | PixelFormat::Bgr => 3,
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+            PixelFormat::Gray => 1,
+        }
+    }
+}
+
+/// 8 bit YUV layouts as webcams deliver them, with the chroma at half resolution.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum YuvLayout {
+    /// A Y plane, then one plane of interleaved `u, v` pairs at half width and height.
+    Nv12,
+    /// Y, U and V planes, U and V at half width and height.
+    I420,
+    /// One plane of `y0, u, y1, v` for every two pixels.
+    Yuyv,
+}
+
+/// The format a frame was given in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FrameFormat {
+    Packed(PixelFormat),
+    Yuv(YuvLayout),
+}
+
+#[derive(Clone, Debug)]
+struct Plane<'a> {
+    data: Cow<'a, [u8]>,
+    stride: usize,
+}
+
+#[derive(Clone, Debug)]
+enum Pixels<'a> {
+    Rgb(Cow<'a, RgbImage>),
+    Raw {
+        data: Cow<'a, [u8]>,
+        format: PixelFormat,
+        stride: usize,
+    },
+    Yuv {
+        layout: YuvLayout,
+        planes: Vec<Plane<'a>>,
+        luma_only: bool,
+    },
+}
+
+/// An image handed to the backends. A `Frame` keeps the pixels in the format they were given in,
+/// borrowed where possible, and converts them to the 8 bit RGB the backends work on the first time a
+/// backend asks, once per frame rather than once per backend.
+#[derive(Clone, Debug)]
+pub struct Frame<'a> {
+    width: u32,
+    height: u32,
+    pixels: Pixels<'a>,
+    rgb: OnceLock<RgbImage>,
+    gray: OnceLock<GrayImage>,
+}
+
+impl<'a> Frame<'a> {
+    fn with_pixels(width: u32, height: u32, pixels: Pixels<'a>) -> Self {
+        Frame {
+            width,
+            height,
+            pixels,
+            rgb: OnceLock::new(),
+            gray: OnceLock::new(),
+        }
+    }
+
+    /// Tightly packed `r, g, b` rows.
+    pub fn from_rgb(width: u32, height: u32, data: Vec<u8>) -> Result<Self, FacialProcessingError> {
+        let expected = width as usize * height as usize * 3;
+        if data.len() != expected {
+            return Err(FacialProcessingError::InvalidFrame(format!(
+                "{}x{} RGB needs {} bytes, got {}",
+                width,
+                height,
+                expected,
+                data.len()
+            )));
+        }
+        match ImageBuffer::from_raw(width, height, data) {
+            Some(image) => Ok(Frame::from(image)),
+            None => Err(FacialProcessingError::InvalidFrame(format!(
+                "{}x{} RGB does not fit in memory",
+                width, height
+            ))),
+        }
+    }
+
+    /// Borrow tightly packed rows of `format` pixels.
+    pub fn from_raw(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+    ) -> Result<Self, FacialProcessingError> {
+        Frame::from_raw_strided(
+            data,
+            width,
+            height,
+            width as usize * format.bytes_per_pixel(),
+            format,
+        )
+    }
+
+    /// Borrow rows of `format` pixels that start `stride` bytes apart, such as a crop of a larger
+    /// buffer or rows padded for alignment.
+    pub fn from_raw_strided(
+        data: &'a [u8],
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Result<Self, FacialProcessingError> {
+        Frame::raw(Cow::Borrowed(data), width, height, stride, format)
+    }
+
+    /// Borrow an 8 bit BGR, BGRA or gray `Mat`, such as one read from a `VideoCapture`. A `Mat`
+    /// that is not continuous, such as a region of a larger one, is copied.
+    pub fn from_mat(mat: &'a Mat) -> Result<Self, FacialProcessingError> {
+        let format = match mat.typ() {
+            Ok(CV_8UC3) => PixelFormat::Bgr,
+            Ok(CV_8UC4) => PixelFormat::Bgra,
+            Ok(CV_8UC1) => PixelFormat::Gray,
+            Ok(other) => {
+                return Err(FacialProcessingError::InvalidFrame(format!(
+                    "Mat type {} is not 8 bit BGR, BGRA or gray",
+                    other
+                )))
+            }
+            Err(why) => return Err(FacialProcessingError::InvalidFrame(why.to_string())),
+        };
+        let (width, height) = (mat.cols().max(0) as u32, mat.rows().max(0) as u32);
+        let stride = width as usize * format.bytes_per_pixel();
+        let data = match mat.is_continuous() {
+            Ok(true) => match mat.data_bytes() {
+                Ok(bytes) => Cow::Borrowed(bytes),
+                Err(why) => return Err(FacialProcessingError::InvalidFrame(why.to_string())),
+            },
+            Ok(false) => {
+                let mut continuous = mat_init!();
+                if let Err(why) = mat.copy_to(&mut continuous) {
+                    return Err(FacialProcessingError::InvalidFrame(why.to_string()));
+                }
+                match continuous.data_bytes() {
+                    Ok(bytes) => Cow::Owned(bytes.to_vec()),
+                    Err(why) => return Err(FacialProcessingError::InvalidFrame(why.to_string())),
+                }
+            }
+            Err(why) => return Err(FacialProcessingError::InvalidFrame(why.to_string())),
+        };
+        Frame::raw(data, width, height, stride, format)
+    }
+
+    fn raw(
+        data: Cow<'a, [u8]>,
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Result<Self, FacialProcessingError> {
+        let row_len = width as usize * format.bytes_per_pixel();
+        if let Err(why) = check_plane(&format!("{:?}", format), &data, stride, row_len, height) {
+            return Err(why);
+        }
+        Ok(Frame::with_pixels(
+            width,
+            height,
+            Pixels::Raw {
+                data,
+                format,
+                stride,
+            },
+        ))
+    }
+
+    /// Borrow an NV12 frame: the Y plane and the interleaved UV plane, each with its stride.
+    pub fn from_nv12(
+        y: &'a [u8],
+        y_stride: usize,
+        uv: &'a [u8],
+        uv_stride: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, FacialProcessingError> {
+        let chroma_width = (width as usize).div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+        if let Err(why) = check_plane("NV12 Y", y, y_stride, width as usize, height) {
+            return Err(why);
+        }
+        if let Err(why) = check_plane("NV12 UV", uv, uv_stride, chroma_width * 2, chroma_height) {
+            return Err(why);
+        }
+        Ok(Frame::yuv(
+            YuvLayout::Nv12,
+            vec![plane(y, y_stride), plane(uv, uv_stride)],
+            width,
+            height,
+        ))
+    }
+
+    /// Borrow an I420 frame: the Y, U and V planes, each with its stride.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_i420(
+        y: &'a [u8],
+        y_stride: usize,
+        u: &'a [u8],
+        u_stride: usize,
+        v: &'a [u8],
+        v_stride: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, FacialProcessingError> {
+        let chroma_width = (width as usize).div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+        if let Err(why) = check_plane("I420 Y", y, y_stride, width as usize, height) {
+            return Err(why);
+        }
+        if let Err(why) = check_plane("I420 U", u, u_stride, chroma_width, chroma_height) {
+            return Err(why);
+        }
+        if let Err(why) = check_plane("I420 V", v, v_stride, chroma_width, chroma_height) {
+            return Err(why);
+        }
+        Ok(Frame::yuv(
+            YuvLayout::I420,
+            vec![plane(y, y_stride), plane(u, u_stride), plane(v, v_stride)],
+            width,
+            height,
+        ))
+    }
+
+    /// Borrow a YUYV (YUY2) frame.
+    pub fn from_yuyv(
+        data: &'a [u8],
+        stride: usize,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, FacialProcessingError> {
+        let row_len = (width as usize).div_ceil(2) * 4;
+        if let Err(why) = check_plane("YUYV", data, stride, row_len, height) {
+            return Err(why);
+        }
+        Ok(Frame::yuv(
+            YuvLayout::Yuyv,
+            vec![plane(data, stride)],
+            width,
+            height,
+        ))
+    }
+
+    fn yuv(layout: YuvLayout, planes: Vec<Plane<'a>>, width: u32, height: u32) -> Self {
+        Frame::with_pixels(
+            width,
+            height,
+            Pixels::Yuv {
+                layout,
+                planes,
+                luma_only: false,
+            },
+        )
+    }
+
+    /// Skip the chroma of a YUV frame, so `image` is the Y plane copied into gray RGB, the same values
+    /// as `luma`. Detection and landmarking rarely need color, and this avoids the conversion. No
+    /// effect on other formats.
+    pub fn luma_only(self) -> Self {
+        match self.pixels {
+            Pixels::Yuv { layout, planes, .. } => Frame::with_pixels(
+                self.width,
+                self.height,
+                Pixels::Yuv {
+                    layout,
+                    planes,
+                    luma_only: true,
+                },
+            ),
+            pixels => Frame::with_pixels(self.width, self.height, pixels),
+        }
+    }
+
+    /// The frame as 8 bit RGB, converted on the first call if it was given in another format.
+    pub fn image(&self) -> &RgbImage {
+        match &self.pixels {
+            Pixels::Rgb(image) => image,
+            pixels => self
+                .rgb
+                .get_or_init(|| pixels_to_rgb(pixels, self.width, self.height)),
+        }
+    }
+
+    /// The frame as 8 bit gray. For YUV and gray frames this is a copy of the luma, without any
+    /// color conversion, so YUV keeps the range it was given in (usually 16 to 235) just like
+    /// `image` of a `luma_only` frame.
+    pub fn luma(&self) -> &GrayImage {
+        self.gray.get_or_init(|| match &self.pixels {
+            Pixels::Raw {
+                data,
+                format: PixelFormat::Gray,
+                stride,
+            } => copy_luma(data, *stride, 1, self.width, self.height),
+            Pixels::Yuv { layout, planes, .. } => copy_luma(
+                &planes[0].data,
+                planes[0].stride,
+                luma_step(*layout),
+                self.width,
+                self.height,
+            ),
+            _ => grayscale(self.image()),
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The format the frame was given in.
+    pub fn format(&self) -> FrameFormat {
+        match &self.pixels {
+            Pixels::Rgb(_) => FrameFormat::Packed(PixelFormat::Rgb),
+            Pixels::Raw { format, .. } => FrameFormat::Packed(*format),
+            Pixels::Yuv { layout, .. } => FrameFormat::Yuv(*layout),
+        }
+    }
+
+    // The data, format and stride of a frame given as packed pixels other than RGB, for backends
+    // that can use those pixels without converting them.
+    pub(crate) fn packed(&self) -> Option<(&[u8], PixelFormat, usize)> {
+        match &self.pixels {
+            Pixels::Raw {
+                data,
+                format,
+                stride,
+            } => Some((data, *format, *stride)),
+            _ => None,
+        }
+    }
+
+    /// Copy any borrowed pixels, so the frame can outlive its source buffer. Conversions already
+    /// done are kept.
+    pub fn into_owned(self) -> Frame<'static> {
+        let pixels = match self.pixels {
+            Pixels::Rgb(image) => Pixels::Rgb(Cow::Owned(image.into_owned())),
+            Pixels::Raw {
+                data,
+                format,
+                stride,
+            } => Pixels::Raw {
+                data: Cow::Owned(data.into_owned()),
+                format,
+                stride,
+            },
+            Pixels::Yuv {
+                layout,
+                planes,
+                luma_only,
+            } => Pixels::Yuv {
+                layout,
+                planes: planes
+                    .into_iter()
+                    .map(|plane| Plane {
+                        data: Cow::Owned(plane.data.into_owned()),
+                        stride: plane.stride,
+                    })
+                    .collect(),
+                luma_only,
+            },
+        };
+        Frame {
+            width: self.width,
+            height: self.height,
+            pixels,
+            rgb: self.rgb,
+            gray: self.gray,
+        }
+    }
+
+    /// Take the RGB image, converting or cloning it if the frame does not own one.
+    pub fn into_image(self) -> RgbImage {
+        match self.pixels {
+            Pixels::Rgb(image) => image.into_owned(),
+            pixels => match self.rgb.into_inner() {
+                Some(image) => image,
+                None => pixels_to_rgb(&pixels, self.width, self.height),
+            },
+        }
+    }
+}
+
+impl Frame<'static> {
+    /// Like `from_raw_strided`, taking ownership of the data.
+    pub fn from_raw_owned(
+        data: Vec<u8>,
+        width: u32,
+        height: u32,
+        stride: usize,
+        format: PixelFormat,
+    ) -> Result<Self, FacialProcessingError> {
+        Frame::raw(Cow::Owned(data), width, height, stride, format)
+    }
+
+    /// Copy any 8 bit image view. Views are not kept, so this converts right away.
+    pub fn from_view<V, P>(view: &V) -> Self
+    where
+        V: GenericImageView<Pixel = P>,
+        P: Pixel<Subpixel = u8> + 'static,
+    {
+        let (width, height) = view.dimensions();
+        Frame::from(ImageBuffer::from_fn(width, height, |x, y| {
+            view.get_pixel(x, y).to_rgb()
+        }))
+    }
+}
+
+fn plane(data: &[u8], stride: usize) -> Plane<'_> {
+    Plane {
+        data: Cow::Borrowed(data),
+        stride,
+    }
+}
+
+// Make sure `rows` rows of `row_len` bytes, `stride` apart, fit in `data`.
+fn check_plane(
+    name: &str,
+    data: &[u8],
+    stride: usize,
+    row_len: usize,
+    rows: u32,
+) -> Result<(), FacialProcessingError> {
+    if stride < row_len {
+        return Err(FacialProcessingError::InvalidFrame(format!(
+            "{} stride of {} is shorter than a row of {} bytes",
+            name, stride, row_len
+        )));
+    }
+    // the last row does not need its padding
+    let needed = match rows {
+        0 => Some(0),
+        _ => stride
+            .checked_mul(rows as usize - 1)
+            .and_then(|padded| padded.checked_add(row_len)),
+    };
+    let needed = match needed {
+        Some(n) => n,
+        None => {
+            return Err(FacialProcessingError::InvalidFrame(format!(
+                "{} rows with a stride of {} do not fit in memory",
+                rows, stride
+            )))
+        }
+    };
+    if data.len() < needed {
+        return Err(FacialProcessingError::InvalidFrame(format!(
+            "{} needs {} bytes for {} rows, got {}",
+            name,
+            needed,
+            rows,
+            data.len()
+        )));
+    }
+    Ok(())
+}
+
+fn pixels_to_rgb(pixels: &Pixels, width: u32, height: u32) -> RgbImage {
+    let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+    match pixels {
+        Pixels::Rgb(image) => return image.clone().into_owned(),
+        Pixels::Raw {
+            data,
+            format,
+            stride,
+        } => {
+            let bytes_per_pixel = format.bytes_per_pixel();
+            for y in 0..height as usize {
+                let row = &data[y * stride..y * stride + width as usize * bytes_per_pixel];
+                for pixel in row.chunks_exact(bytes_per_pixel) {
+                    match format {
+                        PixelFormat::Rgb | PixelFormat::Rgba => rgb.extend_from_slice(&pixel[..3]),
+                        PixelFormat::Bgr | PixelFormat::Bgra => {
+                            rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]])
+                        }
+                        PixelFormat::Gray => rgb.extend_from_slice(&[pixel[0], pixel[0], pixel[0]]),
+                    }
+                }
+            }
+        }
+        Pixels::Yuv {
+            layout,
+            planes,
+            luma_only: true,
+        } => {
+            let (luma, step) = (&planes[0], luma_step(*layout));
+            for y in 0..height as usize {
+                let row = &luma.data[y * luma.stride..];
+                for x in 0..width as usize {
+                    let gray = row[x * step];
+                    rgb.extend_from_slice(&[gray, gray, gray]);
+                }
+            }
+        }
+        Pixels::Yuv {
+            layout,
+            planes,
+            luma_only: false,
+        } => {
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    let (luma, u, v) = yuv_at(*layout, planes, x, y);
+                    rgb.extend_from_slice(&yuv_to_rgb(luma, u, v));
+                }
+            }
+        }
+    }
+    // the size was checked when the frame was made
+    ImageBuffer::from_raw(width, height, rgb).unwrap_or_default()
+}
+
+// The Y, U and V samples for one pixel.
+fn yuv_at(layout: YuvLayout, planes: &[Plane], x: usize, y: usize) -> (u8, u8, u8) {
+    let luma = &planes[0];
+    match layout {
+        YuvLayout::Nv12 => {
+            let uv = &planes[1];
+            let chroma = (y / 2) * uv.stride + (x / 2) * 2;
+            (
+                luma.data[y * luma.stride + x],
+                uv.data[chroma],
+                uv.data[chroma + 1],
+            )
+        }
+        YuvLayout::I420 => {
+            let (u, v) = (&planes[1], &planes[2]);
+            (
+                luma.data[y * luma.stride + x],
+                u.data[(y / 2) * u.stride + x / 2],
+                v.data[(y / 2) * v.stride + x / 2],
+            )
+        }
+        YuvLayout::Yuyv => {
+            let pair = y * luma.stride + (x / 2) * 4;
+            (
+                luma.data[pair + (x % 2) * 2],
+                luma.data[pair + 1],
+                luma.data[pair + 3],
+            )
+        }
+    }
+}
+
+// BT.601 limited range, in fixed point.
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |value: i32| ((value + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+    ]
+}
+
+// How far apart the Y samples of a row are in the first plane.
+fn luma_step(layout: YuvLayout) -> usize {
+    match layout {
+        YuvLayout::Yuyv => 2,
+        _ => 1,
+    }
+}
+
+// Copy every `step`th byte of `width` pixels from each row.
+fn copy_luma(data: &[u8], stride: usize, step: usize, width: u32, height: u32) -> GrayImage {
+    let mut gray = Vec::with_capacity(width as usize * height as usize);
+    for y in 0..height as usize {
+        let row = &data[y * stride..];
+        gray.extend((0..width as usize).map(|x| row[x * step]));
+    }
+    ImageBuffer::from_raw(width, height, gray).unwrap_or_default()
+}
+
+impl<'a> From<&'a RgbImage> for Frame<'a> {
+    fn from(image: &'a RgbImage) -> Self {
+        Frame::with_pixels(
+            image.width(),
+            image.height(),
+            Pixels::Rgb(Cow::Borrowed(image)),
+        )
+    }
+}
+
+impl From<RgbImage> for Frame<'static> {
+    fn from(image: RgbImage) -> Self {
+        Frame::with_pixels(
+            image.width(),
+            image.height(),
+            Pixels::Rgb(Cow::Owned(image)),
+        )
+    }
+}
+
+// Borrow an 8 bit `ImageBuffer` of another pixel type without converting it yet.
+macro_rules! frame_from_buffer {
+    ($pixel:ident, $format:expr) => {
+        impl<'a, C: Deref<Target = [u8]>> From<&'a ImageBuffer<$pixel<u8>, C>> for Frame<'a> {
+            fn from(image: &'a ImageBuffer<$pixel<u8>, C>) -> Self {
+                let (width, height) = image.dimensions();
+                Frame::with_pixels(
+                    width,
+                    height,
+                    Pixels::Raw {
+                        data: Cow::Borrowed(&**image),
+                        format: $format,
+                        stride: width as usize * $format.bytes_per_pixel(),
+                    },
+                )
+            }
+        }
+    };
+}
+
+frame_from_buffer!(Rgba, PixelFormat::Rgba);
+frame_from_buffer!(Bgr, PixelFormat::Bgr);
+frame_from_buffer!(Bgra, PixelFormat::Bgra);
+frame_from_buffer!(Luma, PixelFormat::Gray);
+
+impl<'a> From<&'a DynamicImage> for Frame<'a> {
+    fn from(image: &'a DynamicImage) -> Self {
+        match image {
+            DynamicImage::ImageRgb8(rgb) => Frame::from(rgb),
+            DynamicImage::ImageRgba8(rgba) => Frame::from(rgba),
+            DynamicImage::ImageBgr8(bgr) => Frame::from(bgr),
+            DynamicImage::ImageBgra8(bgra) => Frame::from(bgra),
+            DynamicImage::ImageLuma8(gray) => Frame::from(gray),
+            // 16 bit and gray with alpha have no raw format, so they are converted now
+            other => Frame::from(other.to_rgb8()),
+        }
+    }
+}
+
+impl From<DynamicImage> for Frame<'static> {
+    fn from(image: DynamicImage) -> Self {
+        let (width, height) = image.dimensions();
+        let (data, format) = match image {
+            DynamicImage::ImageRgb8(rgb) => return Frame::from(rgb),
+            DynamicImage::ImageRgba8(rgba) => (rgba.into_raw(), PixelFormat::Rgba),
+            DynamicImage::ImageBgr8(bgr) => (bgr.into_raw(), PixelFormat::Bgr),
+            DynamicImage::ImageBgra8(bgra) => (bgra.into_raw(), PixelFormat::Bgra),
+            DynamicImage::ImageLuma8(gray) => (gray.into_raw(), PixelFormat::Gray),
+            other => return Frame::from(other.to_rgb8()),
+        };
+        Frame::with_pixels(
+            width,
+            height,
+            Pixels::Raw {
+                data: Cow::Owned(data),
+                format,
+                stride: width as usize * format.bytes_per_pixel(),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_rgb_checks_the_size() {
+        assert!(Frame::from_rgb(2, 2, vec![0; 12]).is_ok());
+        assert!(Frame::from_rgb(2, 2, vec![0; 11]).is_err());
+        assert!(Frame::from_rgb(2, 2, vec![0; 13]).is_err());
+    }
+
+    #[test]
+    fn raw_frames_check_stride_and_size() {
+        let data = [0_u8; 16];
+        // a row of 2 BGR pixels is 6 bytes
+        assert!(Frame::from_raw_strided(&data, 2, 2, 5, PixelFormat::Bgr).is_err());
+        // the last row does not need its padding: 8 + 6 bytes
+        assert!(Frame::from_raw_strided(&data[..14], 2, 2, 8, PixelFormat::Bgr).is_ok());
+        assert!(Frame::from_raw_strided(&data[..13], 2, 2, 8, PixelFormat::Bgr).is_err());
+        assert!(Frame::from_raw(&data[..11], 2, 2, PixelFormat::Bgr).is_err());
+        assert!(Frame::from_raw(&data, 0, 0, PixelFormat::Bgr).is_ok());
+    }
+
+    #[test]
+    fn bgr_with_padding_converts_to_rgb() {
+        #[rustfmt::skip]
+        let data = [
+            1, 2, 3, 4, 5, 6, 99, 99,
+            7, 8, 9, 10, 11, 12,
+        ];
+        let frame = Frame::from_raw_strided(&data, 2, 2, 8, PixelFormat::Bgr).unwrap();
+        assert_eq!(frame.format(), FrameFormat::Packed(PixelFormat::Bgr));
+        assert_eq!(
+            frame.image().as_raw(),
+            &vec![3, 2, 1, 6, 5, 4, 9, 8, 7, 12, 11, 10]
+        );
+    }
+
+    #[test]
+    fn bgra_and_rgba_drop_alpha() {
+        let data = [1, 2, 3, 255, 4, 5, 6, 0];
+        let bgra = Frame::from_raw(&data, 2, 1, PixelFormat::Bgra).unwrap();
+        assert_eq!(bgra.image().as_raw(), &vec![3, 2, 1, 6, 5, 4]);
+        let rgba = Frame::from_raw(&data, 2, 1, PixelFormat::Rgba).unwrap();
+        assert_eq!(rgba.image().as_raw(), &vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn gray_converts_to_rgb_and_keeps_its_luma() {
+        let data = [10, 20, 0, 30, 40, 0];
+        let frame = Frame::from_raw_strided(&data, 2, 2, 3, PixelFormat::Gray).unwrap();
+        assert_eq!(
+            frame.image().as_raw(),
+            &vec![10, 10, 10, 20, 20, 20, 30, 30, 30, 40, 40, 40]
+        );
+        assert_eq!(frame.luma().as_raw(), &vec![10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn check_plane_rejects_sizes_that_overflow() {
+        assert!(check_plane("Y", &[], usize::MAX, 1, 3).is_err());
+        assert!(check_plane("Y", &[], usize::MAX / 2 + 1, 1, 3).is_err());
+        assert!(check_plane("Y", &[0; 4], usize::MAX, 4, 1).is_ok());
+    }
+
+    #[test]
+    fn yuv_to_rgb_limited_range() {
+        assert_eq!(yuv_to_rgb(16, 128, 128), [0, 0, 0]);
+        assert_eq!(yuv_to_rgb(235, 128, 128), [255, 255, 255]);
+        assert_eq!(yuv_to_rgb(126, 128, 128), [128, 128, 128]);
+        // BT.601 red, green and blue
+        let close = |a: [u8; 3], b: [u8; 3]| {
+            a.iter()
+                .zip(&b)
+                .all(|(a, b)| (*a as i32 - *b as i32).abs() <= 2)
+        };
+        assert!(close(yuv_to_rgb(81, 90, 240), [255, 0, 0]));
+        assert!(close(yuv_to_rgb(145, 54, 34), [0, 255, 0]));
+        assert!(close(yuv_to_rgb(41, 240, 110), [0, 0, 255]));
+    }
+
+    #[test]
+    fn nv12_samples_chroma_per_two_by_two_block() {
+        #[rustfmt::skip]
+        let y = [
+            0, 1, 2, 0,
+            3, 4, 5, 0,
+            6, 7, 8, 0,
+        ];
+        // 2 chroma columns of (u, v), 2 chroma rows, 1 byte of padding
+        #[rustfmt::skip]
+        let uv = [
+            10, 11, 12, 13, 0,
+            14, 15, 16, 17,
+        ];
+        let frame = Frame::from_nv12(&y, 4, &uv, 5, 3, 3).unwrap();
+        let planes = match &frame.pixels {
+            Pixels::Yuv { planes, .. } => planes,
+            _ => unreachable!(),
+        };
+        assert_eq!(yuv_at(YuvLayout::Nv12, planes, 0, 0), (0, 10, 11));
+        assert_eq!(yuv_at(YuvLayout::Nv12, planes, 1, 1), (4, 10, 11));
+        assert_eq!(yuv_at(YuvLayout::Nv12, planes, 2, 1), (5, 12, 13));
+        assert_eq!(yuv_at(YuvLayout::Nv12, planes, 2, 2), (8, 16, 17));
+        assert!(Frame::from_nv12(&y, 4, &uv[..8], 5, 3, 3).is_err());
+    }
+
+    #[test]
+    fn i420_samples_chroma_per_two_by_two_block() {
+        let y = [0, 1, 2, 3, 4, 5, 6, 7];
+        let (u, v) = ([10, 11, 0, 12, 13], [20, 21, 0, 22, 23]);
+        let frame = Frame::from_i420(&y, 4, &u, 3, &v, 3, 4, 2).unwrap();
+        let planes = match &frame.pixels {
+            Pixels::Yuv { planes, .. } => planes,
+            _ => unreachable!(),
+        };
+        assert_eq!(yuv_at(YuvLayout::I420, planes, 1, 1), (5, 10, 20));
+        assert_eq!(yuv_at(YuvLayout::I420, planes, 2, 0), (2, 11, 21));
+        assert_eq!(yuv_at(YuvLayout::I420, planes, 3, 1), (7, 11, 21));
+    }
+
+    #[test]
+    fn yuyv_shares_chroma_between_pixel_pairs() {
+        #[rustfmt::skip]
+        let data = [
+            1, 10, 2, 20, 3, 11, 4, 21, 0, 0,
+            5, 12, 6, 22, 7, 13, 8, 23,
+        ];
+        let frame = Frame::from_yuyv(&data, 10, 4, 2).unwrap();
+        let planes = match &frame.pixels {
+            Pixels::Yuv { planes, .. } => planes,
+            _ => unreachable!(),
+        };
+        assert_eq!(yuv_at(YuvLayout::Yuyv, planes, 0, 0), (1, 10, 20));
+        assert_eq!(yuv_at(YuvLayout::Yuyv, planes, 1, 0), (2, 10, 20));
+        assert_eq!(yuv_at(YuvLayout::Yuyv, planes, 3, 0), (4, 11, 21));
+        assert_eq!(yuv_at(YuvLayout::Yuyv, planes, 2, 1), (7, 13, 23));
+        assert_eq!(frame.luma().as_raw(), &vec![1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn luma_only_image_is_the_luma() {
+        let y = [16, 100, 235, 50];
+        let uv = [200, 60];
+        let frame = Frame::from_nv12(&y, 2, &uv, 2, 2, 2).unwrap().luma_only();
+        let expected: Vec<u8> = y.iter().flat_map(|l| vec![*l; 3]).collect();
+        assert_eq!(frame.image().as_raw(), &expected);
+        assert_eq!(frame.luma().as_raw(), &y.to_vec());
+    }
+
+    #[test]
+    fn into_owned_keeps_the_pixels() {
+        let data = vec![1, 2, 3];
+        let frame = Frame::from_raw(&data, 1, 1, PixelFormat::Bgr)
+            .unwrap()
+            .into_owned();
+        drop(data);
+        assert_eq!(frame.into_image().as_raw(), &vec![3, 2, 1]);
+    }
+}
@@ -0,0 +1,290 @@
+use crate::error::FacialProcessingError;
+use std::{
+    fmt::Write as FmtWrite,
+    fs::{read_to_string, write},
+    path::Path,
+};
+
+/// dlib's recommended distance threshold for its face encoder: two faces closer than this are
+/// likely the same person.
+pub const DEFAULT_MATCH_THRESHOLD: f64 = 0.6;
+
+/// A face descriptor, such as the 128 values dlib's face encoder produces.
+#[derive(Clone, Debug, Default, PartialOrd, PartialEq)]
+pub struct FaceEmbedding {
+    values: Vec<f64>,
+}
+
+impl FaceEmbedding {
+    pub fn new(values: Vec<f64>) -> Self {
+        FaceEmbedding { values }
+    }
+
+    /// Euclidean distance, or infinity if the embeddings have different sizes.
+    pub fn distance(&self, other: &FaceEmbedding) -> f64 {
+        if self.values.len() != other.values.len() {
+            return f64::INFINITY;
+        }
+        self.values
+            .iter()
+            .zip(&other.values)
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Get a reference to the embedding's values.
+    pub fn values(&self) -> &Vec<f64> {
+        &self.values
+    }
+}
+
+/// A named person and the embeddings enrolled for them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Identity {
+    name: String,
+    embeddings: Vec<FaceEmbedding>,
+}
+
+impl Identity {
+    pub fn new(name: String) -> Self {
+        Identity {
+            name,
+            embeddings: vec![],
+        }
+    }
+
+    /// The distance to the closest enrolled embedding.
+    pub fn distance(&self, embedding: &FaceEmbedding) -> f64 {
+        self.embeddings
+            .iter()
+            .map(|e| e.distance(embedding))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// Get a reference to the identity's name.
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    /// Get a reference to the identity's embeddings.
+    pub fn embeddings(&self) -> &Vec<FaceEmbedding> {
+        &self.embeddings
+    }
+}
+
+#[derive(Clone, Debug, PartialOrd, PartialEq)]
+pub struct IdentityMatch {
+    pub name: String,
+    pub distance: f64,
+}
+
+/// Known identities that faces are matched against. Enroll several embeddings per person (different
+/// angles and lighting) for more reliable matches.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityGallery {
+    identities: Vec<Identity>,
+    threshold: f64,
+}
+
+impl IdentityGallery {
+    pub fn new(threshold: f64) -> Self {
+        IdentityGallery {
+            identities: vec![],
+            threshold,
+        }
+    }
+
+    /// Add an embedding to `name`, creating the identity if it is new. Names are saved one per line,
+    /// so they cannot be empty, have control characters (such as newlines) or start or end with
+    /// whitespace.
+    pub fn enroll(
+        &mut self,
+        name: &str,
+        embedding: FaceEmbedding,
+    ) -> Result<(), FacialProcessingError> {
+        if name.is_empty() || name.trim() != name || name.chars().any(char::is_control) {
+            return Err(FacialProcessingError::InvalidIdentityName(name.to_string()));
+        }
+        match self.identities.iter_mut().find(|i| i.name == name) {
+            Some(identity) => identity.embeddings.push(embedding),
+            None => self.identities.push(Identity {
+                name: name.to_string(),
+                embeddings: vec![embedding],
+            }),
+        }
+        Ok(())
+    }
+
+    /// Remove `name` and all its embeddings. Returns whether it was enrolled.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let before = self.identities.len();
+        self.identities.retain(|i| i.name != name);
+        self.identities.len() != before
+    }
+
+    /// The closest identity, if it is within the threshold.
+    pub fn match_face(&self, embedding: &FaceEmbedding) -> Option<IdentityMatch> {
+        self.identities
+            .iter()
+            .map(|identity| IdentityMatch {
+                name: identity.name.clone(),
+                distance: identity.distance(embedding),
+            })
+            .filter(|m| m.distance <= self.threshold)
+            .min_by(|a, b| {
+                a.distance
+                    .partial_cmp(&b.distance)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            })
+    }
+
+    /// Write the gallery to a plain text file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FacialProcessingError> {
+        let mut out = String::new();
+        let _ = writeln!(out, "threshold {}", self.threshold);
+        for identity in &self.identities {
+            let _ = writeln!(out, "identity {}", identity.name);
+            for embedding in &identity.embeddings {
+                let _ = write!(out, "embedding");
+                for value in &embedding.values {
+                    let _ = write!(out, " {}", value);
+                }
+                let _ = writeln!(out);
+            }
+        }
+
+        match write(path, out) {
+            Ok(_) => Ok(()),
+            Err(why) => Err(FacialProcessingError::IoError(why.to_string())),
+        }
+    }
+
+    /// Load a gallery previously written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FacialProcessingError> {
+        let contents = match read_to_string(path) {
+            Ok(c) => c,
+            Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+        };
+
+        let mut gallery = IdentityGallery::default();
+        for (line_no, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            let (key, rest) = match line.find(' ') {
+                Some(split) => (&line[..split], line[split..].trim()),
+                None => (line, ""),
+            };
+            match key {
+                "" => continue,
+                // names can have spaces, so take the rest of the line
+                "identity" => gallery.identities.push(Identity::new(rest.to_string())),
+                "threshold" | "embedding" => {
+                    let mut values = vec![];
+                    for field in rest.split_whitespace() {
+                        match field.parse::<f64>() {
+                            Ok(v) => values.push(v),
+                            Err(why) => {
+                                return Err(FacialProcessingError::IoError(format!(
+                                    "Line {}: {}",
+                                    line_no + 1,
+                                    why.to_string()
+                                )))
+                            }
+                        }
+                    }
+                    if key == "threshold" {
+                        match values.first() {
+                            Some(t) => gallery.threshold = *t,
+                            None => {
+                                return Err(FacialProcessingError::IoError(format!(
+                                    "Line {}: missing threshold",
+                                    line_no + 1
+                                )))
+                            }
+                        }
+                    } else {
+                        match gallery.identities.last_mut() {
+                            Some(identity) => identity.embeddings.push(FaceEmbedding::new(values)),
+                            None => {
+                                return Err(FacialProcessingError::IoError(format!(
+                                    "Line {}: embedding before any identity",
+                                    line_no + 1
+                                )))
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    return Err(FacialProcessingError::IoError(format!(
+                        "Line {}: unexpected entry {}",
+                        line_no + 1,
+                        key
+                    )))
+                }
+            }
+        }
+        Ok(gallery)
+    }
+
+    /// Get a reference to the gallery's identities.
+    pub fn identities(&self) -> &Vec<Identity> {
+        &self.identities
+    }
+
+    /// Get the gallery's match threshold.
+    pub fn threshold(&self) -> f64 {
+        self.threshold
+    }
+
+    /// Set the gallery's match threshold.
+    pub fn set_threshold(&mut self, threshold: f64) {
+        self.threshold = threshold;
+    }
+}
+
+impl Default for IdentityGallery {
+    fn default() -> Self {
+        IdentityGallery::new(DEFAULT_MATCH_THRESHOLD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enroll_rejects_names_that_do_not_survive_a_save() {
+        let mut gallery = IdentityGallery::default();
+        for name in &["", " Alice", "Alice ", "Al\nice", "Al\tice"] {
+            assert!(gallery.enroll(name, FaceEmbedding::new(vec![0.0])).is_err());
+        }
+        assert!(gallery.identities().is_empty());
+        assert!(gallery
+            .enroll("Alice Smith", FaceEmbedding::new(vec![0.0]))
+            .is_ok());
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let mut gallery = IdentityGallery::new(0.45);
+        gallery
+            .enroll("Alice Smith", FaceEmbedding::new(vec![0.1, -0.25, 1e-7]))
+            .unwrap();
+        gallery
+            .enroll("Alice Smith", FaceEmbedding::new(vec![0.3, 0.5, -2.0]))
+            .unwrap();
+        gallery
+            .enroll("Bob", FaceEmbedding::new(vec![1.0 / 3.0, 0.0, 42.0]))
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "facial_processing_gallery_{}.txt",
+            std::process::id()
+        ));
+        gallery.save(&path).unwrap();
+        let loaded = IdentityGallery::load(&path);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(loaded.unwrap(), gallery);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::utils::misc::LeftRight;
+use std::sync::Arc;
+
+/// Where each facial region lives in a landmark model's output. `Left`/`Right` are image sides, so the
+/// left eye is the subject's right eye, the same as the iBUG 68 point markup.
+/// Regions the model does not have are `None`.
+#[derive(Clone, Debug, Default, PartialOrd, PartialEq, Eq, Hash)]
+pub struct LayoutDefinition {
+    pub point_count: usize,
+    /// Clockwise from the left corner: left corner, 2 upper lid points, right corner, 2 lower lid points.
+    pub left_eye: Option<[usize; 6]>,
+    pub right_eye: Option<[usize; 6]>,
+    /// Left to right.
+    pub left_eyebrow: Option<[usize; 5]>,
+    pub right_eyebrow: Option<[usize; 5]>,
+    /// Left corner, clockwise over the top lip.
+    pub mouth_outer: Option<[usize; 12]>,
+    /// Left corner, clockwise over the top lip.
+    pub mouth_inner: Option<[usize; 8]>,
+    /// Top of the bridge to the tip.
+    pub nose_line: Option<[usize; 4]>,
+    /// Left to right.
+    pub nose_bottom: Option<[usize; 5]>,
+    /// Left to right, through the chin.
+    pub jaw: Option<[usize; 17]>,
+    /// Nose tip, chin, left eye outer corner, right eye outer corner, left mouth corner, right mouth corner.
+    pub pnp: Option<[usize; 6]>,
+    /// Outer then inner corner, left eye first. Unlike `left_eye`, small layouts can usually provide this.
+    pub eye_corners: Option<[[usize; 2]; 2]>,
+    pub nose_tip: Option<usize>,
+}
+
+static FIVE_POINT: LayoutDefinition = LayoutDefinition {
+    point_count: 5,
+    left_eye: None,
+    right_eye: None,
+    left_eyebrow: None,
+    right_eyebrow: None,
+    mouth_outer: None,
+    mouth_inner: None,
+    nose_line: None,
+    nose_bottom: None,
+    jaw: None,
+    pnp: None,
+    eye_corners: Some([[2, 3], [0, 1]]),
+    nose_tip: Some(4),
+};
+
+static IBUG_68: LayoutDefinition = LayoutDefinition {
+    point_count: 68,
+    left_eye: Some([36, 37, 38, 39, 40, 41]),
+    right_eye: Some([42, 43, 44, 45, 46, 47]),
+    left_eyebrow: Some([17, 18, 19, 20, 21]),
+    right_eyebrow: Some([22, 23, 24, 25, 26]),
+    mouth_outer: Some([48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59]),
+    mouth_inner: Some([60, 61, 62, 63, 64, 65, 66, 67]),
+    nose_line: Some([27, 28, 29, 30]),
+    nose_bottom: Some([31, 32, 33, 34, 35]),
+    jaw: Some([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]),
+    pnp: Some([30, 8, 36, 45, 48, 54]),
+    eye_corners: Some([[36, 39], [45, 42]]),
+    nose_tip: Some(30),
+};
+
+static WFLW_98: LayoutDefinition = LayoutDefinition {
+    point_count: 98,
+    left_eye: Some([60, 61, 63, 64, 65, 67]),
+    right_eye: Some([68, 69, 71, 72, 73, 75]),
+    left_eyebrow: Some([33, 34, 35, 36, 37]),
+    right_eyebrow: Some([42, 43, 44, 45, 46]),
+    mouth_outer: Some([76, 77, 78, 79, 80, 81, 82, 83, 84, 85, 86, 87]),
+    mouth_inner: Some([88, 89, 90, 91, 92, 93, 94, 95]),
+    nose_line: Some([51, 52, 53, 54]),
+    nose_bottom: Some([55, 56, 57, 58, 59]),
+    jaw: Some([
+        0, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32,
+    ]),
+    pnp: Some([54, 16, 60, 72, 76, 82]),
+    eye_corners: Some([[60, 64], [72, 68]]),
+    nose_tip: Some(54),
+};
+
+// The insightface 2d106det markup, as used by OpenVTuber. The contour is not stored in order.
+static POINT_106: LayoutDefinition = LayoutDefinition {
+    point_count: 106,
+    left_eye: Some([35, 41, 42, 39, 37, 36]),
+    right_eye: Some([89, 95, 96, 93, 91, 90]),
+    left_eyebrow: None,
+    right_eyebrow: None,
+    mouth_outer: Some([52, 64, 63, 71, 67, 68, 61, 58, 59, 53, 56, 55]),
+    mouth_inner: Some([65, 66, 62, 70, 69, 57, 60, 54]),
+    nose_line: Some([72, 73, 74, 86]),
+    nose_bottom: None,
+    jaw: Some([
+        1, 10, 12, 14, 16, 3, 5, 7, 0, 23, 21, 19, 32, 30, 28, 26, 17,
+    ]),
+    pnp: Some([86, 0, 35, 93, 52, 61]),
+    eye_corners: Some([[35, 39], [93, 89]]),
+    nose_tip: Some(86),
+};
+
+static MEDIAPIPE_468: LayoutDefinition = LayoutDefinition {
+    point_count: 468,
+    left_eye: Some([33, 160, 158, 133, 153, 144]),
+    right_eye: Some([362, 385, 387, 263, 373, 380]),
+    left_eyebrow: Some([70, 63, 105, 66, 107]),
+    right_eyebrow: Some([336, 296, 334, 293, 300]),
+    mouth_outer: Some([61, 40, 37, 0, 267, 270, 291, 321, 314, 17, 84, 91]),
+    mouth_inner: Some([78, 81, 13, 311, 308, 402, 14, 178]),
+    nose_line: Some([168, 6, 197, 195]),
+    nose_bottom: Some([98, 97, 2, 326, 327]),
+    jaw: Some([
+        234, 93, 132, 58, 172, 136, 150, 176, 152, 400, 379, 365, 397, 288, 361, 323, 454,
+    ]),
+    pnp: Some([1, 152, 33, 263, 61, 291]),
+    eye_corners: Some([[33, 133], [263, 362]]),
+    nose_tip: Some(1),
+};
+
+/// The landmark markup a backend produces.
+#[derive(Clone, Debug, PartialOrd, PartialEq, Eq, Hash)]
+pub enum LandmarkLayout {
+    /// dlib's `shape_predictor_5_face_landmarks`: eye corners and the bottom of the nose.
+    FivePoint,
+    /// iBUG 300-W, used by dlib's 68 point predictor.
+    Ibug68,
+    /// WFLW.
+    Wflw98,
+    /// insightface 2d106det.
+    Point106,
+    /// MediaPipe face mesh (without the iris refinement points).
+    MediaPipe468,
+    Custom(Arc<LayoutDefinition>),
+}
+
+impl LandmarkLayout {
+    pub fn definition(&self) -> &LayoutDefinition {
+        match self {
+            LandmarkLayout::FivePoint => &FIVE_POINT,
+            LandmarkLayout::Ibug68 => &IBUG_68,
+            LandmarkLayout::Wflw98 => &WFLW_98,
+            LandmarkLayout::Point106 => &POINT_106,
+            LandmarkLayout::MediaPipe468 => &MEDIAPIPE_468,
+            LandmarkLayout::Custom(def) => def,
+        }
+    }
+
+    /// A short human readable name, for error messages.
+    pub fn name(&self) -> &'static str {
+        match self {
+            LandmarkLayout::FivePoint => "5 point",
+            LandmarkLayout::Ibug68 => "iBUG 68 point",
+            LandmarkLayout::Wflw98 => "WFLW 98 point",
+            LandmarkLayout::Point106 => "106 point",
+            LandmarkLayout::MediaPipe468 => "MediaPipe 468 point",
+            LandmarkLayout::Custom(_) => "custom",
+        }
+    }
+
+    pub fn point_count(&self) -> usize {
+        self.definition().point_count
+    }
+
+    pub fn eye(&self, side: LeftRight) -> Option<[usize; 6]> {
+        match side {
+            LeftRight::Left => self.definition().left_eye,
+            LeftRight::Right => self.definition().right_eye,
+        }
+    }
+
+    pub fn eyebrow(&self, side: LeftRight) -> Option<[usize; 5]> {
+        match side {
+            LeftRight::Left => self.definition().left_eyebrow,
+            LeftRight::Right => self.definition().right_eyebrow,
+        }
+    }
+
+    pub fn mouth_outer(&self) -> Option<[usize; 12]> {
+        self.definition().mouth_outer
+    }
+
+    pub fn mouth_inner(&self) -> Option<[usize; 8]> {
+        self.definition().mouth_inner
+    }
+
+    pub fn nose_line(&self) -> Option<[usize; 4]> {
+        self.definition().nose_line
+    }
+
+    pub fn nose_bottom(&self) -> Option<[usize; 5]> {
+        self.definition().nose_bottom
+    }
+
+    pub fn jaw(&self) -> Option<[usize; 17]> {
+        self.definition().jaw
+    }
+
+    pub fn pnp(&self) -> Option<[usize; 6]> {
+        self.definition().pnp
+    }
+
+    pub fn eye_corners(&self, side: LeftRight) -> Option<[usize; 2]> {
+        let corners = self.definition().eye_corners?;
+        match side {
+            LeftRight::Left => Some(corners[0]),
+            LeftRight::Right => Some(corners[1]),
+        }
+    }
+
+    pub fn nose_tip(&self) -> Option<usize> {
+        self.definition().nose_tip
+    }
+}
+
+impl Default for LandmarkLayout {
+    fn default() -> Self {
+        LandmarkLayout::Ibug68
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILT_IN: [LandmarkLayout; 5] = [
+        LandmarkLayout::FivePoint,
+        LandmarkLayout::Ibug68,
+        LandmarkLayout::Wflw98,
+        LandmarkLayout::Point106,
+        LandmarkLayout::MediaPipe468,
+    ];
+
+    fn regions(layout: &LandmarkLayout) -> Vec<(&'static str, Vec<usize>)> {
+        let mut regions = vec![];
+        for side in [LeftRight::Left, LeftRight::Right].iter() {
+            if let Some(eye) = layout.eye(*side) {
+                regions.push(("eye", eye.to_vec()));
+            }
+            if let Some(eyebrow) = layout.eyebrow(*side) {
+                regions.push(("eyebrow", eyebrow.to_vec()));
+            }
+            if let Some(corners) = layout.eye_corners(*side) {
+                regions.push(("eye corners", corners.to_vec()));
+            }
+        }
+        if let Some(mouth) = layout.mouth_outer() {
+            regions.push(("mouth outer", mouth.to_vec()));
+        }
+        if let Some(mouth) = layout.mouth_inner() {
+            regions.push(("mouth inner", mouth.to_vec()));
+        }
+        if let Some(nose) = layout.nose_line() {
+            regions.push(("nose line", nose.to_vec()));
+        }
+        if let Some(nose) = layout.nose_bottom() {
+            regions.push(("nose bottom", nose.to_vec()));
+        }
+        if let Some(jaw) = layout.jaw() {
+            regions.push(("jaw", jaw.to_vec()));
+        }
+        if let Some(pnp) = layout.pnp() {
+            regions.push(("pnp", pnp.to_vec()));
+        }
+        if let Some(tip) = layout.nose_tip() {
+            regions.push(("nose tip", vec![tip]));
+        }
+        regions
+    }
+
+    #[test]
+    fn region_indices_are_in_range() {
+        for layout in BUILT_IN.iter() {
+            for (region, indices) in regions(layout) {
+                for index in &indices {
+                    assert!(
+                        *index < layout.point_count(),
+                        "{} {} index {} is out of range",
+                        layout.name(),
+                        region,
+                        index
+                    );
+                }
+                let mut unique = indices.clone();
+                unique.sort_unstable();
+                unique.dedup();
+                assert_eq!(
+                    unique.len(),
+                    indices.len(),
+                    "{} {} repeats an index",
+                    layout.name(),
+                    region
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn eye_corners_match_the_eyes() {
+        for layout in BUILT_IN.iter() {
+            if let (Some(eye), Some(corners)) = (
+                layout.eye(LeftRight::Left),
+                layout.eye_corners(LeftRight::Left),
+            ) {
+                assert_eq!(corners, [eye[0], eye[3]], "{}", layout.name());
+            }
+            if let (Some(eye), Some(corners)) = (
+                layout.eye(LeftRight::Right),
+                layout.eye_corners(LeftRight::Right),
+            ) {
+                assert_eq!(corners, [eye[3], eye[0]], "{}", layout.name());
+            }
+        }
+    }
+
+    #[test]
+    fn point_106_regions() {
+        let layout = LandmarkLayout::Point106;
+        assert_eq!(layout.point_count(), 106);
+        assert!(regions(&layout).len() > 8);
+        assert_eq!(layout.nose_tip(), Some(86));
+        assert!(layout.nose_line().unwrap().contains(&86));
+        assert!(layout.eyebrow(LeftRight::Left).is_none());
+    }
+}
@@ -1,21 +1,35 @@
-use crate::{error::FacialProcessingError, mat_init, utils::face::FaceLandmark, vector};
-use cv_convert::TryFromCv;
+use crate::{
+    error::FacialProcessingError,
+    face_processor_trait::FaceProcessorTrait,
+    mat_init,
+    utils::{
+        calibration::CameraIntrinsics,
+        face::FaceLandmark,
+        face_model::{planar_subset, FaceModel3D},
+        layout::LandmarkLayout,
+        model_config::{DLibDetector, DetectorModelConfig, LandmarkModelConfig, OpenCVDetector},
+        pose::{HeadPose, PoseFit},
+    },
+};
 #[cfg(feature = "dlib")]
 use dlib_face_recognition::{Point, Rectangle};
 use image::imageops::FilterType;
-use nalgebra::Matrix3;
+use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector3};
 use opencv::{
     calib3d::{
-        rodrigues, rq_decomp3x3, solve_pnp, solve_pnp_ransac, SOLVEPNP_AP3P, SOLVEPNP_DLS,
-        SOLVEPNP_EPNP, SOLVEPNP_IPPE, SOLVEPNP_IPPE_SQUARE, SOLVEPNP_ITERATIVE, SOLVEPNP_MAX_COUNT,
-         SOLVEPNP_UPNP, SOLVEPNP_SQPNP, 
-    },
-    core::{
-        Mat, MatExprTrait, Point2d, Point3d, ToInputArray, ToOutputArray, Vec3d, Vector,
-        _InputOutputArray, CV_64F,
+        project_points, solve_pnp, solve_pnp_generic, solve_pnp_ransac, SolvePnPMethod,
+        SOLVEPNP_AP3P, SOLVEPNP_DLS, SOLVEPNP_EPNP, SOLVEPNP_IPPE, SOLVEPNP_IPPE_SQUARE,
+        SOLVEPNP_ITERATIVE, SOLVEPNP_P3P, SOLVEPNP_SQPNP, SOLVEPNP_UPNP,
     },
+    core::{Mat, Point2d, Point2f, Point3d, Rect, ToInputArray, ToOutputArray, Vec3d, Vector},
+};
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    fmt::{Debug, Display, Formatter},
+    ops::Sub,
+    sync::Arc,
 };
-use std::{fmt::{Display, Formatter}, ops::Sub};
 
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
 pub enum LeftRight {
@@ -28,12 +42,25 @@ impl Default for LeftRight {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum SupportedProcesses {
     Detection,
     Alignment,
     Eyesolation, // haha im so funny
     PoseEstimation,
+    Recognition,
+}
+
+impl SupportedProcesses {
+    pub fn all() -> [SupportedProcesses; 5] {
+        [
+            SupportedProcesses::Detection,
+            SupportedProcesses::Alignment,
+            SupportedProcesses::Eyesolation,
+            SupportedProcesses::PoseEstimation,
+            SupportedProcesses::Recognition,
+        ]
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
@@ -81,6 +108,24 @@ impl From<Point2D> for Point2d {
     }
 }
 
+impl From<Point2D> for Point2f {
+    fn from(val: Point2D) -> Self {
+        Point2f::new(val.x as f32, val.y as f32)
+    }
+}
+
+impl From<Point2d> for Point2D {
+    fn from(pt: Point2d) -> Self {
+        Point2D::new(pt.x, pt.y)
+    }
+}
+
+impl From<Point2f> for Point2D {
+    fn from(pt: Point2f) -> Self {
+        Point2D::new(pt.x as f64, pt.y as f64)
+    }
+}
+
 impl Sub<Point2D> for Point2D {
     type Output = Point2D;
 
@@ -89,6 +134,47 @@ impl Sub<Point2D> for Point2D {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
+pub struct Point3D {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+impl Point3D {
+    pub fn new(x: f64, y: f64, z: f64) -> Self {
+        Point3D { x, y, z }
+    }
+    pub fn x(&self) -> f64 {
+        self.x
+    }
+    pub fn y(&self) -> f64 {
+        self.y
+    }
+    pub fn z(&self) -> f64 {
+        self.z
+    }
+}
+impl Default for Point3D {
+    fn default() -> Self {
+        Point3D {
+            x: 0.0_f64,
+            y: 0.0_f64,
+            z: 0.0_f64,
+        }
+    }
+}
+impl Display for Point3D {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}, {}, {})", self.x, self.y, self.z)
+    }
+}
+
+impl From<Point3D> for Point3d {
+    fn from(val: Point3D) -> Self {
+        Point3d::new(val.x, val.y, val.z)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
 pub struct FloatingPoint2D {
     pub x: f64,
@@ -121,6 +207,15 @@ impl BoundingBox {
     pub fn high_point(&self) -> Point2D {
         Point2D::new(self.x_maximum as f64, self.y_maximum as f64)
     }
+    /// The box with both axes multiplied, for moving between a frame and a resized copy of it.
+    pub fn scaled(&self, x: f64, y: f64) -> Self {
+        BoundingBox {
+            x_minumum: (self.x_minumum as f64 * x).round() as i32,
+            x_maximum: (self.x_maximum as f64 * x).round() as i32,
+            y_minumum: (self.y_minumum as f64 * y).round() as i32,
+            y_maximum: (self.y_maximum as f64 * y).round() as i32,
+        }
+    }
     pub fn center(&self) -> FloatingPoint2D {
         FloatingPoint2D::new(
             (self.x_maximum - self.x_minumum) as f64 / 2_f64,
@@ -140,6 +235,39 @@ impl Display for BoundingBox {
     }
 }
 
+impl From<BoundingBox> for Rect {
+    fn from(bbox: BoundingBox) -> Self {
+        let x = bbox.x_minumum.min(bbox.x_maximum);
+        let y = bbox.y_minumum.min(bbox.y_maximum);
+        Rect::new(
+            x,
+            y,
+            bbox.x_minumum.max(bbox.x_maximum) - x,
+            bbox.y_minumum.max(bbox.y_maximum) - y,
+        )
+    }
+}
+
+impl From<Rect> for BoundingBox {
+    fn from(rect: Rect) -> Self {
+        BoundingBox {
+            x_minumum: rect.x,
+            x_maximum: rect.x + rect.width,
+            y_minumum: rect.y,
+            y_maximum: rect.y + rect.height,
+        }
+    }
+}
+
+/// For OpenCV functions that take several faces, such as `Facemark::fit`.
+pub fn bboxes_to_rects(bboxes: &[BoundingBox]) -> Vector<Rect> {
+    let mut rects = Vector::new();
+    for bbox in bboxes {
+        rects.push(Rect::from(*bbox));
+    }
+    rects
+}
+
 #[cfg(feature = "dlib")]
 impl From<Rectangle> for BoundingBox {
     fn from(r: Rectangle) -> Self {
@@ -163,13 +291,68 @@ impl Into<Rectangle> for BoundingBox {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash)]
+pub enum AngleUnit {
+    Degrees,
+    Radians,
+}
+impl Default for AngleUnit {
+    fn default() -> Self {
+        AngleUnit::Degrees
+    }
+}
+
+/// The order of the per-axis rotations making up a set of euler angles. `Xyz` means the rotation
+/// matrix is `Rx(x) * Ry(y) * Rz(z)`, so the z rotation is applied to a point first.
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash)]
+pub enum RotationOrder {
+    Xyz,
+    Xzy,
+    Yxz,
+    Yzx,
+    Zxy,
+    Zyx,
+}
+impl RotationOrder {
+    // axis indices, outermost first
+    fn axes(&self) -> [usize; 3] {
+        match self {
+            RotationOrder::Xyz => [0, 1, 2],
+            RotationOrder::Xzy => [0, 2, 1],
+            RotationOrder::Yxz => [1, 0, 2],
+            RotationOrder::Yzx => [1, 2, 0],
+            RotationOrder::Zxy => [2, 0, 1],
+            RotationOrder::Zyx => [2, 1, 0],
+        }
+    }
+}
+impl Default for RotationOrder {
+    fn default() -> Self {
+        RotationOrder::Xyz
+    }
+}
+
+/// Rotations about the x (pitch), y (yaw) and z (roll) axes.
+/// For a `HeadPose` these are in camera axes (x right, y down, z into the scene), relative to a
+/// face looking straight into the camera.
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
 pub struct EulerAngles {
     pub x: f64,
     pub y: f64,
     pub z: f64,
+    pub order: RotationOrder,
+    pub unit: AngleUnit,
 }
 impl EulerAngles {
+    pub fn new(x: f64, y: f64, z: f64, order: RotationOrder, unit: AngleUnit) -> Self {
+        EulerAngles {
+            x,
+            y,
+            z,
+            order,
+            unit,
+        }
+    }
     pub fn x(&self) -> f64 {
         self.x
     }
@@ -179,16 +362,154 @@ impl EulerAngles {
     pub fn z(&self) -> f64 {
         self.z
     }
+    /// Nodding. Rotation about the x axis.
+    pub fn pitch(&self) -> f64 {
+        self.x
+    }
+    /// Shaking your head. Rotation about the y axis.
+    pub fn yaw(&self) -> f64 {
+        self.y
+    }
+    /// Tilting your head. Rotation about the z axis.
+    pub fn roll(&self) -> f64 {
+        self.z
+    }
+    pub fn order(&self) -> RotationOrder {
+        self.order
+    }
+    pub fn unit(&self) -> AngleUnit {
+        self.unit
+    }
+
+    pub fn to_degrees(&self) -> Self {
+        match self.unit {
+            AngleUnit::Degrees => *self,
+            AngleUnit::Radians => EulerAngles {
+                x: self.x.to_degrees(),
+                y: self.y.to_degrees(),
+                z: self.z.to_degrees(),
+                order: self.order,
+                unit: AngleUnit::Degrees,
+            },
+        }
+    }
+
+    pub fn to_radians(&self) -> Self {
+        match self.unit {
+            AngleUnit::Radians => *self,
+            AngleUnit::Degrees => EulerAngles {
+                x: self.x.to_radians(),
+                y: self.y.to_radians(),
+                z: self.z.to_radians(),
+                order: self.order,
+                unit: AngleUnit::Radians,
+            },
+        }
+    }
+
+    /// Wrap every angle into (-180, 180] degrees (or (-pi, pi] radians).
+    pub fn wrapped(&self) -> Self {
+        let half_turn = match self.unit {
+            AngleUnit::Degrees => 180_f64,
+            AngleUnit::Radians => std::f64::consts::PI,
+        };
+        let wrap = |angle: f64| {
+            let wrapped = (angle + half_turn).rem_euclid(2.0 * half_turn) - half_turn;
+            if wrapped == -half_turn {
+                half_turn
+            } else {
+                wrapped
+            }
+        };
+        EulerAngles {
+            x: wrap(self.x),
+            y: wrap(self.y),
+            z: wrap(self.z),
+            order: self.order,
+            unit: self.unit,
+        }
+    }
+
+    /// The same rotation, expressed in another rotation order.
+    pub fn with_order(&self, order: RotationOrder) -> Self {
+        EulerAngles::from_rotation_matrix(&self.to_rotation_matrix(), order, self.unit)
+    }
+
+    pub fn to_rotation_matrix(&self) -> Matrix3<f64> {
+        let radians = self.to_radians();
+        let angles = [radians.x, radians.y, radians.z];
+        let axes = [Vector3::x_axis(), Vector3::y_axis(), Vector3::z_axis()];
+        let mut matrix = Matrix3::identity();
+        for axis in self.order.axes().iter() {
+            matrix *= Rotation3::from_axis_angle(&axes[*axis], angles[*axis]).matrix();
+        }
+        matrix
+    }
+
+    pub fn to_quaternion(&self) -> UnitQuaternion<f64> {
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(
+            self.to_rotation_matrix(),
+        ))
+    }
+
+    pub fn from_rotation_matrix(
+        matrix: &Matrix3<f64>,
+        order: RotationOrder,
+        unit: AngleUnit,
+    ) -> Self {
+        let [i, j, k] = order.axes();
+        // +1 for the cyclic orders (xyz, yzx, zxy)
+        let parity = if (j + 3 - i) % 3 == 1 { 1_f64 } else { -1_f64 };
+
+        let sin_middle = (parity * matrix[(i, k)]).max(-1.0).min(1.0);
+        let middle = sin_middle.asin();
+        let (outer, inner) = if sin_middle.abs() < 1.0 - 1e-9 {
+            (
+                (-parity * matrix[(j, k)]).atan2(matrix[(k, k)]),
+                (-parity * matrix[(i, j)]).atan2(matrix[(i, i)]),
+            )
+        } else {
+            // gimbal lock, only the sum is known so put it all in the outer angle
+            ((parity * matrix[(k, j)]).atan2(matrix[(j, j)]), 0_f64)
+        };
+
+        let mut angles = [0_f64; 3];
+        angles[i] = outer;
+        angles[j] = middle;
+        angles[k] = inner;
+        let radians = EulerAngles::new(angles[0], angles[1], angles[2], order, AngleUnit::Radians);
+        match unit {
+            AngleUnit::Radians => radians,
+            AngleUnit::Degrees => radians.to_degrees(),
+        }
+    }
+
+    pub fn from_quaternion(
+        quaternion: &UnitQuaternion<f64>,
+        order: RotationOrder,
+        unit: AngleUnit,
+    ) -> Self {
+        EulerAngles::from_rotation_matrix(quaternion.to_rotation_matrix().matrix(), order, unit)
+    }
 }
+/// From the output of `rq_decomp3x3`, which is in degrees. Its `Q` is `Qz^T * Qy^T * Qx^T`, which
+/// is `Rz(z) * Ry(y) * Rx(x)`, so the order is `Zyx`.
 impl From<Vec3d> for EulerAngles {
     fn from(vec: Vec3d) -> Self {
         EulerAngles {
             x: *vec.get(0).unwrap(),
             y: *vec.get(1).unwrap(),
             z: *vec.get(2).unwrap(),
+            order: RotationOrder::Zyx,
+            unit: AngleUnit::Degrees,
         }
     }
 }
+impl From<EulerAngles> for UnitQuaternion<f64> {
+    fn from(angles: EulerAngles) -> Self {
+        angles.to_quaternion()
+    }
+}
 
 impl Display for EulerAngles {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -196,15 +517,103 @@ impl Display for EulerAngles {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum BackendProviders {
-    OpenVTuber,
+    OpenVTuber {
+        face_detector_path: String,
+        face_alignment_path: String,
+        face_eyesolator_path: String,
+    },
     DLib {
+        face_detector: DLibDetector,
         face_alignment_path: String,
+        /// `Ibug68` for `shape_predictor_68_face_landmarks.dat`, `FivePoint` for
+        /// `shape_predictor_5_face_landmarks.dat`.
+        landmark_layout: LandmarkLayout,
+        /// `dlib_face_recognition_resnet_model_v1.dat`, for face embeddings.
+        face_encoder_path: Option<String>,
     },
+    Onnx {
+        detector: DetectorModelConfig,
+        landmark: LandmarkModelConfig,
+    },
+    OpenCV {
+        detector: OpenCVDetector,
+        facemark_lbf_path: String,
+    },
+    /// A backend from outside this crate.
+    Custom(CustomBackend),
     None,
 }
 
+impl BackendProviders {
+    /// Shorthand for `BackendProviders::Custom(CustomBackend::new(name, factory))`.
+    pub fn custom<F>(name: &str, factory: F) -> Self
+    where
+        F: Fn() -> Result<Box<dyn FaceProcessorTrait>, FacialProcessingError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        BackendProviders::Custom(CustomBackend::new(name, factory))
+    }
+}
+
+type BackendFactory =
+    dyn Fn() -> Result<Box<dyn FaceProcessorTrait>, FacialProcessingError> + Send + Sync;
+
+/// Creates a user provided `FaceProcessorTrait` implementation when the `FaceProcessor` is built,
+/// so it gets image scaling, detection filtering, pose tracking and the eye and mouth calculations
+/// like the built-in backends.
+/// Clones share the factory, and compare equal to each other, so stages given clones of the same
+/// `CustomBackend` share one instance.
+#[derive(Clone)]
+pub struct CustomBackend {
+    name: String,
+    factory: Arc<BackendFactory>,
+}
+
+impl CustomBackend {
+    pub fn new<F>(name: &str, factory: F) -> Self
+    where
+        F: Fn() -> Result<Box<dyn FaceProcessorTrait>, FacialProcessingError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        CustomBackend {
+            name: name.to_string(),
+            factory: Arc::new(factory),
+        }
+    }
+
+    /// Run the factory.
+    pub fn create(&self) -> Result<Box<dyn FaceProcessorTrait>, FacialProcessingError> {
+        (self.factory)()
+    }
+
+    /// Get a reference to the custom backend's name.
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+}
+
+impl Debug for CustomBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomBackend")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl PartialEq for CustomBackend {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.factory, &other.factory)
+    }
+}
+
+/// The size frames are resized to before detection and landmarking. Results are mapped back to the
+/// full frame, so only the backends see the smaller image.
 #[derive(Copy, Clone, Debug)]
 pub struct ImageScale {
     pub target_x: u32,
@@ -212,14 +621,10 @@ pub struct ImageScale {
     pub method: FilterType,
 }
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum PnPArguments {
     NoRandsc,
-    Randsc {
-        iter: i32,
-        reproj: f32,
-        conf: f64,
-        inliner: Box<opencv::Result<_InputOutputArray>>,
-    },
+    Randsc { iter: i32, reproj: f32, conf: f64 },
 }
 impl Default for PnPArguments {
     fn default() -> Self {
@@ -227,191 +632,502 @@ impl Default for PnPArguments {
     }
 }
 
+/// The PnP solvers OpenCV provides. See the OpenCV `solvePnP` docs for details on each.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PnPMethod {
+    /// Levenberg-Marquardt refinement of a DLT initial guess. Needs 6 points for non-planar models,
+    /// 4 for planar ones or when refining a previous pose.
+    Iterative,
+    EPnP,
+    /// Needs exactly 4 points, unless used under RANSAC.
+    P3P,
+    /// Redirected to EPnP by OpenCV 4.
+    DLS,
+    /// Redirected to EPnP by OpenCV 4.
+    UPnP,
+    /// Needs exactly 4 points, unless used under RANSAC.
+    AP3P,
+    /// Only valid for planar models, which `PnPSolver` checks.
+    IPPE,
+    /// Needs exactly 4 points (unless used under RANSAC), arranged as a square marker.
+    IPPESquare,
+    SQPnP,
+}
+impl PnPMethod {
+    pub fn flag(&self) -> i32 {
+        match self {
+            PnPMethod::Iterative => SOLVEPNP_ITERATIVE,
+            PnPMethod::EPnP => SOLVEPNP_EPNP,
+            PnPMethod::P3P => SOLVEPNP_P3P,
+            PnPMethod::DLS => SOLVEPNP_DLS,
+            PnPMethod::UPnP => SOLVEPNP_UPNP,
+            PnPMethod::AP3P => SOLVEPNP_AP3P,
+            PnPMethod::IPPE => SOLVEPNP_IPPE,
+            PnPMethod::IPPESquare => SOLVEPNP_IPPE_SQUARE,
+            PnPMethod::SQPnP => SOLVEPNP_SQPNP,
+        }
+    }
+
+    /// The least amount of correspondences this method will accept, for a planar model or not and
+    /// with an extrinsic guess or not.
+    pub fn min_points(&self, planar: bool, use_guess: bool) -> usize {
+        match self {
+            PnPMethod::Iterative if planar || use_guess => 4,
+            PnPMethod::Iterative => 6,
+            PnPMethod::SQPnP => 3,
+            _ => 4,
+        }
+    }
+
+    /// The most correspondences this method will accept, if limited. Under RANSAC the minimal
+    /// solvers only ever see samples of 4, so they take any amount.
+    pub fn max_points(&self, args: &PnPArguments) -> Option<usize> {
+        match (self, args) {
+            (_, PnPArguments::Randsc { .. }) => None,
+            (PnPMethod::P3P | PnPMethod::AP3P | PnPMethod::IPPESquare, _) => Some(4),
+            _ => None,
+        }
+    }
+
+    /// Check that `points` correspondences can be solved with this method.
+    pub fn validate(
+        &self,
+        points: usize,
+        planar: bool,
+        use_guess: bool,
+        args: &PnPArguments,
+    ) -> Result<(), FacialProcessingError> {
+        let min = self.min_points(planar, use_guess);
+        if points < min {
+            return Err(FacialProcessingError::InitializeError(format!(
+                "{:?} needs at least {} points, got {}",
+                self, min, points
+            )));
+        }
+        if let Some(max) = self.max_points(args) {
+            if points > max {
+                return Err(FacialProcessingError::InitializeError(format!(
+                    "{:?} needs at most {} points without RANSAC, got {}",
+                    self, max, points
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `validate`, taking the point count and planarity from the model and also checking that
+    /// the model is planar for `IPPE`.
+    pub fn validate_model(
+        &self,
+        face_model: &FaceModel3D,
+        use_guess: bool,
+        args: &PnPArguments,
+    ) -> Result<(), FacialProcessingError> {
+        let planar = face_model.is_planar();
+        if let Err(why) = self.validate(face_model.points().len(), planar, use_guess, args) {
+            return Err(why);
+        }
+        if *self == PnPMethod::IPPE && !planar {
+            return Err(FacialProcessingError::InitializeError(
+                "IPPE needs a planar face model".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+impl Default for PnPMethod {
+    fn default() -> Self {
+        PnPMethod::EPnP
+    }
+}
+impl TryFrom<i32> for PnPMethod {
+    type Error = FacialProcessingError;
+
+    fn try_from(flag: i32) -> Result<Self, Self::Error> {
+        match flag {
+            SOLVEPNP_ITERATIVE => Ok(PnPMethod::Iterative),
+            SOLVEPNP_EPNP => Ok(PnPMethod::EPnP),
+            SOLVEPNP_P3P => Ok(PnPMethod::P3P),
+            SOLVEPNP_DLS => Ok(PnPMethod::DLS),
+            SOLVEPNP_UPNP => Ok(PnPMethod::UPnP),
+            SOLVEPNP_AP3P => Ok(PnPMethod::AP3P),
+            SOLVEPNP_IPPE => Ok(PnPMethod::IPPE),
+            SOLVEPNP_IPPE_SQUARE => Ok(PnPMethod::IPPESquare),
+            SOLVEPNP_SQPNP => Ok(PnPMethod::SQPnP),
+            _ => Err(FacialProcessingError::InitializeError(format!(
+                "{} is not a valid PNP setting!",
+                flag
+            ))),
+        }
+    }
+}
+
+// Reprojection error (in pixels) used to scale `PoseFit::confidence` when RANSAC has no threshold to offer.
+const DEFAULT_ERROR_SCALE: f64 = 8.0;
+
+// Two solutions closer than this (in radians) are considered the same pose.
+const DISTINCT_POSE_ANGLE: f64 = 0.02;
+
 pub struct PnPSolver {
-    face_3d: Vector<Point3d>,
-    camera_res: Point2D,
+    face_model: FaceModel3D,
+    intrinsics: CameraIntrinsics,
+    calibrated: bool,
     camera_distortion: Mat,
     camera_matrix: Mat,
-    pnp_mode: i32,
-    pnp_extrinsic: bool,
+    pnp_mode: PnPMethod,
     pnp_args: PnPArguments,
 }
 impl PnPSolver {
     pub fn new(
         camera_res: Point2D,
-        calc_mode: Option<i32>,
+        calc_mode: Option<PnPMethod>,
         pnp_args: PnPArguments,
+        face_model: Option<FaceModel3D>,
     ) -> Result<Self, FacialProcessingError> {
-        // Fake 3D Model definition
-        let face_3d: Vector<Point3d> = vector![
-            Point3d::new(0.0, 0.0, 0.0),          // Nose Tip
-            Point3d::new(0.0, -330.0, -65.0),     // Chin
-            Point3d::new(-225.0, 170.0, -135.0),  // Left corner left eye
-            Point3d::new(225.0, 170.0, -135.0),   // Right corner right eye
-            Point3d::new(-150.0, -150.0, -125.0), // Mouth Corner left
-            Point3d::new(150.0, -150.0, -125.0)   // Mouth Corner right
-        ];
-
-        let focal_len = camera_res.x;
-        let center = Point2D::new(camera_res.x / 2_f64, camera_res.y / 2_f64);
-        let camera_matrix_na: Matrix3<f64> = Matrix3::from_row_slice(&[
-            focal_len, 0.0, center.x, 0.0, focal_len, center.y, 0.0, 0.0, 1.0,
-        ]);
-        let camera_matrix = match Mat::try_from_cv(camera_matrix_na) {
+        let intrinsics = CameraIntrinsics::from_resolution(camera_res);
+        let camera_matrix = match intrinsics.camera_matrix_mat() {
             Ok(m) => m,
             Err(why) => {
                 return Err(FacialProcessingError::InitializeError(why.to_string()));
             }
         };
 
-        let camera_distortion = match Mat::zeros(4, 1, CV_64F) {
-            Ok(mut m) => m.a(),
+        let camera_distortion = match intrinsics.distortion_mat() {
+            Ok(m) => m,
             Err(why) => {
                 return Err(FacialProcessingError::InitializeError(why.to_string()));
             }
         };
 
-        let pnp_mode = match calc_mode {
-            Some(mode) => match mode {
-                SOLVEPNP_AP3P | SOLVEPNP_DLS | SOLVEPNP_ITERATIVE | SOLVEPNP_IPPE
-                | SOLVEPNP_IPPE_SQUARE | SOLVEPNP_MAX_COUNT | SOLVEPNP_EPNP
-                | SOLVEPNP_UPNP => mode,
-                _ => {
-                    return Err(FacialProcessingError::InitializeError(format!(
-                        "{} is not a valid PNP setting!",
-                        mode
-                    )))
-                }
-            },
-            None => SOLVEPNP_EPNP,
-        };
+        let face_model = face_model.unwrap_or_default();
+        let pnp_mode = calc_mode.unwrap_or_default();
+        if let Err(why) = pnp_mode.validate_model(&face_model, false, &pnp_args) {
+            return Err(why);
+        }
 
         Ok(PnPSolver {
-            face_3d,
-            camera_res,
+            face_model,
+            intrinsics,
+            calibrated: false,
             camera_distortion,
             camera_matrix,
             pnp_mode,
-            pnp_extrinsic: false,
             pnp_args,
         })
     }
 
-    pub fn raw_forward(&self, data: FaceLandmark) -> Result<(Mat, Mat), FacialProcessingError> {
-        match &self.pnp_args {
-            PnPArguments::NoRandsc => {
-                let mut rvec = mat_init!();
-                let mut tvec = mat_init!();
-
-                let mut fp: Vector<Point2d> = Vector::new();
-                for pt in data.pnp_landmarks().to_vec() {
-                    fp.push(Point2D::into(pt))
-                }
+    pub fn raw_forward(
+        &self,
+        data: FaceLandmark,
+    ) -> Result<(Mat, Mat, PoseFit), FacialProcessingError> {
+        self.raw_forward_with_guess(data, None)
+    }
 
+    /// Like `raw_forward`, but refines from a previous `(rvec, tvec)` if given. OpenCV only uses
+    /// a guess with the iterative solver, so that is what gets used in that case.
+    pub fn raw_forward_with_guess(
+        &self,
+        data: FaceLandmark,
+        guess: Option<(&Mat, &Mat)>,
+    ) -> Result<(Mat, Mat, PoseFit), FacialProcessingError> {
+        let face_model = match self.face_model_for(data.layout()) {
+            Ok(m) => m,
+            Err(why) => return Err(why),
+        };
+        let (mut rvec, mut tvec, use_guess, method) = match guess {
+            Some((rvec, tvec)) => (rvec.clone(), tvec.clone(), true, PnPMethod::Iterative),
+            None => (mat_init!(), mat_init!(), false, self.pnp_mode),
+        };
+        if let Err(why) = method.validate_model(&face_model, use_guess, &self.pnp_args) {
+            return Err(why);
+        }
+        let (face_3d, fp, landmark_indices) = face_model
+            .correspondences(&data, method.min_points(face_model.is_planar(), use_guess))?;
+        let flag = method.flag();
+        let (inliers, error_scale) = match &self.pnp_args {
+            PnPArguments::NoRandsc => {
                 match solve_pnp(
-                    &self.face_3d.input_array().unwrap(),
+                    &face_3d.input_array().unwrap(),
                     &fp.input_array().unwrap(),
                     &self.camera_matrix.input_array().unwrap(),
                     &self.camera_distortion.input_array().unwrap(),
                     &mut rvec.output_array().unwrap(),
                     &mut tvec.output_array().unwrap(),
-                    self.pnp_extrinsic,
-                    self.pnp_mode,
+                    use_guess,
+                    flag,
                 ) {
-                    Ok(b) => {
-                        if b {
-                            return Ok((rvec, tvec));
-                        }
-                        Err(FacialProcessingError::InternalError("PnP Calculation failed".to_string()))
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return Err(FacialProcessingError::InternalError(
+                            "PnP Calculation failed".to_string(),
+                        ))
                     }
-                    Err(why) => Err(FacialProcessingError::InternalError(why.to_string())),
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
                 }
+                (
+                    (0..face_3d.len()).collect::<Vec<usize>>(),
+                    DEFAULT_ERROR_SCALE,
+                )
             }
-            PnPArguments::Randsc {
-                iter,
-                reproj,
-                conf,
-                inliner: _inliner,
-            } => {
-                let mut rvec = mat_init!();
-                let mut tvec = mat_init!();
-                let mut fp: Vector<Point2d> = Vector::new();
-                for pt in data.pnp_landmarks().to_vec() {
-                    fp.push(Point2D::into(pt))
-                }
-                let mut il = opencv::core::no_array().unwrap();
+            PnPArguments::Randsc { iter, reproj, conf } => {
+                let mut il: Vector<i32> = Vector::new();
                 match solve_pnp_ransac(
-                    &self.face_3d.input_array().unwrap(),
+                    &face_3d.input_array().unwrap(),
                     &fp.input_array().unwrap(),
                     &self.camera_matrix.input_array().unwrap(),
                     &self.camera_distortion.input_array().unwrap(),
                     &mut rvec.output_array().unwrap(),
                     &mut tvec.output_array().unwrap(),
-                    self.pnp_extrinsic,
+                    use_guess,
                     *iter,
                     *reproj,
                     *conf,
                     &mut il.output_array().unwrap(),
-                    self.pnp_mode,
+                    flag,
                 ) {
-                    Ok(b) => {
-                        if b {
-                            return Ok((rvec, tvec));
-                        }
-                        Err(FacialProcessingError::InternalError("PnP Calculation failed".to_string()))
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return Err(FacialProcessingError::InternalError(
+                            "PnP Calculation failed".to_string(),
+                        ))
                     }
-                    Err(why) => Err(FacialProcessingError::InternalError(why.to_string())),
+                    Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
                 }
+                (
+                    il.iter().map(|idx| idx as usize).collect::<Vec<usize>>(),
+                    *reproj as f64,
+                )
             }
+        };
+
+        let reprojection_errors = match self.reprojection_errors(&face_3d, &fp, &rvec, &tvec) {
+            Ok(errs) => errs,
+            Err(why) => return Err(why),
+        };
+        let fit = PoseFit::new(
+            &landmark_indices,
+            reprojection_errors,
+            &inliers,
+            error_scale,
+        );
+        Ok((rvec, tvec, fit))
+    }
+
+    /// The distance, in pixels, between every image point and its model point projected with `rvec`/`tvec`.
+    pub fn reprojection_errors(
+        &self,
+        face_3d: &Vector<Point3d>,
+        image_points: &Vector<Point2d>,
+        rvec: &Mat,
+        tvec: &Mat,
+    ) -> Result<Vec<f64>, FacialProcessingError> {
+        let mut projected: Vector<Point2d> = Vector::new();
+        let mut jackobin = mat_init!();
+        if let Err(why) = project_points(
+            &face_3d.input_array().unwrap(),
+            &rvec.input_array().unwrap(),
+            &tvec.input_array().unwrap(),
+            &self.camera_matrix.input_array().unwrap(),
+            &self.camera_distortion.input_array().unwrap(),
+            &mut projected.output_array().unwrap(),
+            &mut jackobin.output_array().unwrap(),
+            0.0,
+        ) {
+            return Err(FacialProcessingError::InternalError(why.to_string()));
         }
+
+        Ok(projected
+            .iter()
+            .zip(image_points.iter())
+            .map(|(proj, actual)| {
+                ((proj.x - actual.x) * (proj.x - actual.x)
+                    + (proj.y - actual.y) * (proj.y - actual.y))
+                    .sqrt()
+            })
+            .collect())
     }
 
-    pub fn forward(&self, data: FaceLandmark) -> Result<EulerAngles, FacialProcessingError> {
+    pub fn forward(&self, data: FaceLandmark) -> Result<HeadPose, FacialProcessingError> {
         match self.raw_forward(data) {
-            Ok((rvec, _tvec)) => {
-                let mut dest = mat_init!();
-                let mut jackobin = mat_init!();
-                if let Err(why) = rodrigues(
-                    &rvec.input_array().unwrap(),
-                    &mut dest.output_array().unwrap(),
-                    &mut jackobin.output_array().unwrap(),
-                ) {
-                    return Err(FacialProcessingError::InternalError(
-                        format!("Failed to calculate rodrigues: {}", why.to_string())
-                    ))
-                }
-                
-
-                let mut mtx_r = mat_init!();
-                let mut mtx_q = mat_init!();
-                let mut qx = mat_init!();
-                let mut qy = mat_init!();
-                let mut qz = mat_init!();
-
-                match rq_decomp3x3(
-                    &dest.input_array().unwrap(),
-                    &mut mtx_r.output_array().unwrap(),
-                    &mut mtx_q.output_array().unwrap(),
-                    &mut qx.output_array().unwrap(),
-                    &mut qy.output_array().unwrap(),
-                    &mut qz.output_array().unwrap(),
-                ) {
-                    Ok(rots) => Ok(EulerAngles::from(rots)),
-                    Err(why) => Err(FacialProcessingError::InternalError(why.to_string())),
+            Ok((rvec, tvec, fit)) => match HeadPose::from_rvec_tvec(&rvec, &tvec) {
+                Ok(pose) => Ok(pose.with_fit(fit)),
+                Err(why) => Err(why),
+            },
+            Err(f) => Err(f),
+        }
+    }
+
+    /// Solve (from `guess` if given), then look for the other pose that explains the image as well.
+    /// IPPE gives both solutions for the model points nearest a plane, and each is refined on every
+    /// point. This returns every distinct pose that was found, primary solution first.
+    pub fn forward_candidates(
+        &self,
+        data: FaceLandmark,
+        guess: Option<(&Mat, &Mat)>,
+    ) -> Result<Vec<HeadPose>, FacialProcessingError> {
+        let primary = match self.raw_forward_with_guess(data.clone(), guess) {
+            Ok((rvec, tvec, fit)) => match HeadPose::from_rvec_tvec(&rvec, &tvec) {
+                Ok(pose) => pose.with_fit(fit),
+                Err(why) => return Err(why),
+            },
+            Err(why) => return Err(why),
+        };
+        let mut candidates = vec![primary];
+
+        let face_model = match self.face_model_for(data.layout()) {
+            Ok(m) => m,
+            Err(why) => return Err(why),
+        };
+        // IPPE needs 4 points, the primary solve may have done with fewer.
+        let (face_3d, fp, _) = match face_model.correspondences(&data, 4) {
+            Ok(c) => c,
+            Err(_) => return Ok(candidates),
+        };
+        let (plane_3d, plane_2d) = planar_subset(&face_3d, &fp);
+        let mut rvecs: Vector<Mat> = Vector::new();
+        let mut tvecs: Vector<Mat> = Vector::new();
+        let mut plane_errors = mat_init!();
+        if let Err(why) = solve_pnp_generic(
+            &plane_3d,
+            &plane_2d,
+            &self.camera_matrix,
+            &self.camera_distortion,
+            &mut rvecs,
+            &mut tvecs,
+            false,
+            SolvePnPMethod::SOLVEPNP_IPPE,
+            &mat_init!(),
+            &mat_init!(),
+            &mut plane_errors,
+        ) {
+            return Err(FacialProcessingError::InternalError(why.to_string()));
+        }
+
+        for (rvec, tvec) in rvecs.iter().zip(tvecs.iter()) {
+            if let Ok((rvec, tvec, fit)) =
+                self.raw_forward_with_guess(data.clone(), Some((&rvec, &tvec)))
+            {
+                if let Ok(pose) = HeadPose::from_rvec_tvec(&rvec, &tvec) {
+                    if candidates.iter().all(|candidate| {
+                        pose.quaternion().angle_to(&candidate.quaternion()) > DISTINCT_POSE_ANGLE
+                    }) {
+                        candidates.push(pose.with_fit(fit));
+                    }
                 }
             }
-            Err(f) => Err(f),
         }
+        Ok(candidates)
+    }
+
+    // A model built for another markup would pair up the wrong points, so fall back to a generic
+    // model on whatever the landmark's layout offers.
+    fn face_model_for(
+        &self,
+        layout: &LandmarkLayout,
+    ) -> Result<Cow<FaceModel3D>, FacialProcessingError> {
+        if layout == self.face_model.layout() {
+            return Ok(Cow::Borrowed(&self.face_model));
+        }
+        match FaceModel3D::fallback_for(layout) {
+            Some(m) => Ok(Cow::Owned(m)),
+            None => Err(FacialProcessingError::UnsupportedRegion {
+                region: "PnP points",
+                layout: layout.name().to_string(),
+            }),
+        }
+    }
+
+    fn update_camera_mats(&mut self) -> Result<(), FacialProcessingError> {
+        self.camera_matrix = match self.intrinsics.camera_matrix_mat() {
+            Ok(m) => m,
+            Err(why) => return Err(why),
+        };
+        self.camera_distortion = match self.intrinsics.distortion_mat() {
+            Ok(m) => m,
+            Err(why) => return Err(why),
+        };
+        Ok(())
     }
 
     /// Get a reference to the pn p solver's camera res.
     pub fn camera_res(&self) -> &Point2D {
-        &self.camera_res
+        self.intrinsics.resolution()
+    }
+
+    /// Set the pn p solver's camera res. Calibrated intrinsics get scaled to the new resolution,
+    /// otherwise the camera matrix gets re-guessed from it.
+    pub fn set_camera_res(&mut self, camera_res: Point2D) -> Result<(), FacialProcessingError> {
+        self.intrinsics = if self.calibrated {
+            match self.intrinsics.scaled_to(camera_res) {
+                Ok(i) => i,
+                Err(why) => return Err(why),
+            }
+        } else {
+            CameraIntrinsics::from_resolution(camera_res)
+        };
+        self.update_camera_mats()
+    }
+
+    /// Use calibrated camera intrinsics (see `CameraIntrinsics::calibrate_chessboard`) instead of the guessed ones.
+    pub fn set_intrinsics(
+        &mut self,
+        intrinsics: &CameraIntrinsics,
+    ) -> Result<(), FacialProcessingError> {
+        self.intrinsics = intrinsics.clone();
+        self.calibrated = true;
+        self.update_camera_mats()
+    }
+
+    /// Get a reference to the pn p solver's camera intrinsics.
+    pub fn intrinsics(&self) -> &CameraIntrinsics {
+        &self.intrinsics
+    }
+
+    /// Set the pn p solver's camera matrix.
+    pub fn set_camera_matrix(
+        &mut self,
+        camera_matrix: Matrix3<f64>,
+    ) -> Result<(), FacialProcessingError> {
+        self.intrinsics.set_camera_matrix(camera_matrix);
+        self.calibrated = true;
+        self.update_camera_mats()
+    }
+
+    /// Set the pn p solver's distortion coefficients.
+    pub fn set_distortion(&mut self, distortion: Vec<f64>) -> Result<(), FacialProcessingError> {
+        self.intrinsics.set_distortion(distortion);
+        self.update_camera_mats()
+    }
+
+    /// Get the pn p solver's method.
+    pub fn method(&self) -> PnPMethod {
+        self.pnp_mode
+    }
+
+    /// Set the pn p solver's method. Fails if the current face model can never satisfy it.
+    pub fn set_method(&mut self, method: PnPMethod) -> Result<(), FacialProcessingError> {
+        if let Err(why) = method.validate_model(&self.face_model, false, &self.pnp_args) {
+            return Err(why);
+        }
+        self.pnp_mode = method;
+        Ok(())
+    }
+
+    /// Get a reference to the pn p solver's face model.
+    pub fn face_model(&self) -> &FaceModel3D {
+        &self.face_model
     }
 
-    /// Set the pn p solver's camera res.
-    pub fn set_camera_res(&mut self, camera_res: Point2D) {
-        self.camera_res = camera_res;
+    /// Set the pn p solver's face model. Fails if the current method cannot use it.
+    pub fn set_face_model(&mut self, face_model: FaceModel3D) -> Result<(), FacialProcessingError> {
+        if let Err(why) = self
+            .pnp_mode
+            .validate_model(&face_model, false, &self.pnp_args)
+        {
+            return Err(why);
+        }
+        self.face_model = face_model;
+        Ok(())
     }
 }
 
@@ -441,3 +1157,159 @@ impl PnPSolver {
 //         let me
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::{
+        calib3d::rq_decomp3x3,
+        core::{Scalar, CV_64F},
+        prelude::*,
+    };
+
+    const ORDERS: [RotationOrder; 6] = [
+        RotationOrder::Xyz,
+        RotationOrder::Xzy,
+        RotationOrder::Yxz,
+        RotationOrder::Yzx,
+        RotationOrder::Zxy,
+        RotationOrder::Zyx,
+    ];
+
+    fn assert_matrix_close(a: &Matrix3<f64>, b: &Matrix3<f64>) {
+        assert!((a - b).amax() < 1e-9, "{} != {}", a, b);
+    }
+
+    fn assert_angles_close(a: &EulerAngles, b: &EulerAngles) {
+        assert_eq!(a.order(), b.order());
+        assert_eq!(a.unit(), b.unit());
+        for (x, y) in [(a.x, b.x), (a.y, b.y), (a.z, b.z)].iter() {
+            assert!((x - y).abs() < 1e-9, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn matrix_round_trip_in_every_order() {
+        let matrix = *Rotation3::new(Vector3::new(0.3, -0.5, 0.2)).matrix();
+        for order in ORDERS.iter() {
+            for unit in [AngleUnit::Degrees, AngleUnit::Radians].iter() {
+                let angles = EulerAngles::from_rotation_matrix(&matrix, *order, *unit);
+                assert_eq!(angles.order(), *order);
+                assert_eq!(angles.unit(), *unit);
+                assert_matrix_close(&angles.to_rotation_matrix(), &matrix);
+            }
+        }
+    }
+
+    #[test]
+    fn angles_round_trip_in_every_order() {
+        for order in ORDERS.iter() {
+            let angles = EulerAngles::new(20.0, -35.0, 50.0, *order, AngleUnit::Degrees);
+            let back = EulerAngles::from_rotation_matrix(
+                &angles.to_rotation_matrix(),
+                *order,
+                AngleUnit::Degrees,
+            );
+            assert_angles_close(&back, &angles);
+
+            let quaternion =
+                EulerAngles::from_quaternion(&angles.to_quaternion(), *order, AngleUnit::Degrees);
+            assert_angles_close(&quaternion, &angles);
+        }
+    }
+
+    #[test]
+    fn gimbal_lock_keeps_the_rotation() {
+        for order in ORDERS.iter() {
+            for middle in [90_f64, -90_f64].iter() {
+                let mut angles = [10_f64, 10_f64, 10_f64];
+                angles[order.axes()[1]] = *middle;
+                let angles =
+                    EulerAngles::new(angles[0], angles[1], angles[2], *order, AngleUnit::Degrees);
+                let matrix = angles.to_rotation_matrix();
+                let back = EulerAngles::from_rotation_matrix(&matrix, *order, AngleUnit::Degrees);
+                assert_matrix_close(&back.to_rotation_matrix(), &matrix);
+            }
+        }
+    }
+
+    #[test]
+    fn with_order_keeps_the_rotation() {
+        let angles = EulerAngles::new(20.0, -35.0, 50.0, RotationOrder::Xyz, AngleUnit::Degrees);
+        for order in ORDERS.iter() {
+            let reordered = angles.with_order(*order);
+            assert_eq!(reordered.order(), *order);
+            assert_matrix_close(
+                &reordered.to_rotation_matrix(),
+                &angles.to_rotation_matrix(),
+            );
+            assert_angles_close(&reordered.with_order(RotationOrder::Xyz), &angles);
+        }
+    }
+
+    #[test]
+    fn degrees_radians_round_trip() {
+        let degrees = EulerAngles::new(180.0, 90.0, -45.0, RotationOrder::Xyz, AngleUnit::Degrees);
+        let radians = degrees.to_radians();
+        assert_eq!(radians.unit(), AngleUnit::Radians);
+        assert_angles_close(
+            &radians,
+            &EulerAngles::new(
+                std::f64::consts::PI,
+                std::f64::consts::FRAC_PI_2,
+                -std::f64::consts::FRAC_PI_4,
+                RotationOrder::Xyz,
+                AngleUnit::Radians,
+            ),
+        );
+        assert_angles_close(&radians.to_degrees(), &degrees);
+        assert_eq!(degrees.to_degrees(), degrees);
+        assert_eq!(radians.to_radians(), radians);
+    }
+
+    #[test]
+    fn wrapped_at_half_turns() {
+        let wrapped =
+            EulerAngles::new(190.0, -180.0, 540.0, RotationOrder::Xyz, AngleUnit::Degrees)
+                .wrapped();
+        assert_angles_close(
+            &wrapped,
+            &EulerAngles::new(-170.0, 180.0, 180.0, RotationOrder::Xyz, AngleUnit::Degrees),
+        );
+        let wrapped =
+            EulerAngles::new(180.0, -190.0, 45.0, RotationOrder::Xyz, AngleUnit::Degrees).wrapped();
+        assert_angles_close(
+            &wrapped,
+            &EulerAngles::new(180.0, 170.0, 45.0, RotationOrder::Xyz, AngleUnit::Degrees),
+        );
+
+        let pi = std::f64::consts::PI;
+        let wrapped =
+            EulerAngles::new(-pi, 1.5 * pi, 0.0, RotationOrder::Xyz, AngleUnit::Radians).wrapped();
+        assert_angles_close(
+            &wrapped,
+            &EulerAngles::new(pi, -0.5 * pi, 0.0, RotationOrder::Xyz, AngleUnit::Radians),
+        );
+    }
+
+    #[test]
+    fn rq_decomp3x3_angles_are_zyx() {
+        let angles = EulerAngles::new(20.0, -35.0, 50.0, RotationOrder::Zyx, AngleUnit::Degrees);
+        let matrix = angles.to_rotation_matrix();
+        let mut src = Mat::new_rows_cols_with_default(3, 3, CV_64F, Scalar::all(0.0)).unwrap();
+        for row in 0..3 {
+            for col in 0..3 {
+                *src.at_2d_mut::<f64>(row as i32, col as i32).unwrap() = matrix[(row, col)];
+            }
+        }
+        let mut mtx_r = mat_init!();
+        let mut mtx_q = mat_init!();
+        let mut q_x = mat_init!();
+        let mut q_y = mat_init!();
+        let mut q_z = mat_init!();
+        let decomposed = EulerAngles::from(
+            rq_decomp3x3(&src, &mut mtx_r, &mut mtx_q, &mut q_x, &mut q_y, &mut q_z).unwrap(),
+        );
+        assert_angles_close(&decomposed, &angles);
+    }
+}
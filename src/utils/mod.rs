@@ -1,6 +1,16 @@
+pub mod calibration;
+pub mod detection;
 pub mod eyes;
 pub mod face;
+pub mod face_model;
+pub mod frame;
+pub mod identity;
+pub mod layout;
 #[macro_use]
 pub mod macros;
 pub mod misc;
+pub mod model_config;
 pub mod mouth;
+pub mod parallel;
+pub mod pose;
+pub mod source;
@@ -0,0 +1,330 @@
+use crate::utils::{
+    layout::LandmarkLayout,
+    misc::{BoundingBox, Point2D},
+};
+use image::{
+    imageops::{crop_imm, replace, resize, FilterType},
+    ImageBuffer, Rgb,
+};
+
+/// How a model expects its image tensor to be laid out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TensorLayout {
+    /// `[1, 3, height, width]`
+    Nchw,
+    /// `[1, height, width, 3]`
+    Nhwc,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChannelOrder {
+    Rgb,
+    Bgr,
+}
+
+/// Turns `u8` pixels into model input as `(pixel - mean) / std`, per channel in `channel_order`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Preprocessing {
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    pub channel_order: ChannelOrder,
+    pub layout: TensorLayout,
+}
+
+impl Preprocessing {
+    /// The shape of the input tensor for an image of this size.
+    pub fn input_shape(&self, width: u32, height: u32) -> [usize; 4] {
+        match self.layout {
+            TensorLayout::Nchw => [1, 3, height as usize, width as usize],
+            TensorLayout::Nhwc => [1, height as usize, width as usize, 3],
+        }
+    }
+
+    /// Flatten `image` into tensor data of `input_shape`.
+    pub fn tensor_data(&self, image: &ImageBuffer<Rgb<u8>, Vec<u8>>) -> Vec<f32> {
+        let (width, height) = image.dimensions();
+        let channels = match self.channel_order {
+            ChannelOrder::Rgb => [0, 1, 2],
+            ChannelOrder::Bgr => [2, 1, 0],
+        };
+        let normalize = |pixel: &Rgb<u8>, channel: usize| {
+            (pixel[channels[channel]] as f32 - self.mean[channel]) / self.std[channel]
+        };
+
+        let mut data = Vec::with_capacity((width * height * 3) as usize);
+        match self.layout {
+            TensorLayout::Nchw => {
+                for channel in 0..3 {
+                    for pixel in image.pixels() {
+                        data.push(normalize(pixel, channel));
+                    }
+                }
+            }
+            TensorLayout::Nhwc => {
+                for pixel in image.pixels() {
+                    for channel in 0..3 {
+                        data.push(normalize(pixel, channel));
+                    }
+                }
+            }
+        }
+        data
+    }
+}
+
+/// How to read faces out of a detector's outputs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DetectorDecoding {
+    /// Ultra-Light-Fast-Generic-Face-Detector: `scores` `[1, N, 2]` then `boxes` `[1, N, 4]`, with
+    /// boxes as `x1, y1, x2, y2` relative to the input size. Needs NMS.
+    UltraFace,
+    /// A single `[1, N, 5]` output of `x1, y1, x2, y2, score` rows, for models exported with their
+    /// post-processing. If not `normalized`, the boxes are in input pixels. With `keypoints` the rows
+    /// are `[1, N, 15]`, followed by the `x, y` of both eyes, the nose tip and both mouth corners.
+    Rows { normalized: bool, keypoints: bool },
+}
+
+/// How to read points out of a landmark model's first output, a flat list of `x, y` pairs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LandmarkDecoding {
+    /// `0..1` across the crop.
+    Normalized,
+    /// `-1..1` across the crop, with 0 at its center.
+    Centered,
+    /// Pixels in the model input.
+    Pixels,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetectorModelConfig {
+    pub model_path: String,
+    pub input_width: u32,
+    pub input_height: u32,
+    pub preprocessing: Preprocessing,
+    pub decoding: DetectorDecoding,
+    pub score_threshold: f32,
+    /// Overlap (IoU) above which the lower scoring of two boxes is dropped.
+    pub nms_threshold: f32,
+}
+
+impl DetectorModelConfig {
+    /// `version-RFB-320.onnx` from Ultra-Light-Fast-Generic-Face-Detector-1MB.
+    pub fn ultraface_rfb_320(model_path: String) -> Self {
+        DetectorModelConfig {
+            model_path,
+            input_width: 320,
+            input_height: 240,
+            preprocessing: Preprocessing {
+                mean: [127.0, 127.0, 127.0],
+                std: [128.0, 128.0, 128.0],
+                channel_order: ChannelOrder::Rgb,
+                layout: TensorLayout::Nchw,
+            },
+            decoding: DetectorDecoding::UltraFace,
+            score_threshold: 0.7,
+            nms_threshold: 0.3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LandmarkModelConfig {
+    pub model_path: String,
+    pub input_width: u32,
+    pub input_height: u32,
+    pub preprocessing: Preprocessing,
+    pub decoding: LandmarkDecoding,
+    pub layout: LandmarkLayout,
+    /// The face box is made square and grown by this much before cropping.
+    pub crop_scale: f32,
+}
+
+impl LandmarkModelConfig {
+    /// insightface's `2d106det.onnx`.
+    pub fn insightface_106(model_path: String) -> Self {
+        LandmarkModelConfig {
+            model_path,
+            input_width: 192,
+            input_height: 192,
+            preprocessing: Preprocessing {
+                mean: [0.0, 0.0, 0.0],
+                std: [1.0, 1.0, 1.0],
+                channel_order: ChannelOrder::Rgb,
+                layout: TensorLayout::Nchw,
+            },
+            decoding: LandmarkDecoding::Centered,
+            layout: LandmarkLayout::Point106,
+            crop_scale: 1.5,
+        }
+    }
+
+    /// OpenVTuber's `coor_2d106.tflite`, the same markup as `insightface_106` converted to NHWC.
+    pub fn openvtuber_106(model_path: String) -> Self {
+        LandmarkModelConfig {
+            preprocessing: Preprocessing {
+                mean: [0.0, 0.0, 0.0],
+                std: [1.0, 1.0, 1.0],
+                channel_order: ChannelOrder::Rgb,
+                layout: TensorLayout::Nhwc,
+            },
+            ..LandmarkModelConfig::insightface_106(model_path)
+        }
+    }
+
+    /// A PFLD model trained on WFLW.
+    pub fn pfld_98(model_path: String) -> Self {
+        LandmarkModelConfig {
+            model_path,
+            input_width: 112,
+            input_height: 112,
+            preprocessing: Preprocessing {
+                mean: [0.0, 0.0, 0.0],
+                std: [255.0, 255.0, 255.0],
+                channel_order: ChannelOrder::Rgb,
+                layout: TensorLayout::Nchw,
+            },
+            decoding: LandmarkDecoding::Normalized,
+            layout: LandmarkLayout::Wflw98,
+            crop_scale: 1.2,
+        }
+    }
+
+    /// Crop the area around `bbox` the model wants and resize it to the input size.
+    /// Also returns the crop in image coordinates, to map the points back with `decode_point`.
+    pub fn crop(
+        &self,
+        image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+        bbox: BoundingBox,
+    ) -> (ImageBuffer<Rgb<u8>, Vec<u8>>, BoundingBox) {
+        let (x_low, x_high) = (
+            bbox.x_minumum.min(bbox.x_maximum),
+            bbox.x_minumum.max(bbox.x_maximum),
+        );
+        let (y_low, y_high) = (
+            bbox.y_minumum.min(bbox.y_maximum),
+            bbox.y_minumum.max(bbox.y_maximum),
+        );
+        let center = Point2D::new((x_low + x_high) as f64 / 2.0, (y_low + y_high) as f64 / 2.0);
+        let size = (x_high - x_low).max(y_high - y_low) as f64 * self.crop_scale as f64;
+        let crop_box = square_around(center, size);
+        (
+            crop_padded(image, crop_box, self.input_width, self.input_height),
+            crop_box,
+        )
+    }
+
+    /// Map a raw model output pair back into the image, given the crop it came from.
+    pub fn decode_point(&self, x: f32, y: f32, crop_box: BoundingBox) -> Point2D {
+        let (rel_x, rel_y) = match self.decoding {
+            LandmarkDecoding::Normalized => (x, y),
+            LandmarkDecoding::Centered => ((x + 1.0) / 2.0, (y + 1.0) / 2.0),
+            LandmarkDecoding::Pixels => (x / self.input_width as f32, y / self.input_height as f32),
+        };
+        Point2D::new(
+            crop_box.x_minumum as f64
+                + rel_x as f64 * (crop_box.x_maximum - crop_box.x_minumum) as f64,
+            crop_box.y_minumum as f64
+                + rel_y as f64 * (crop_box.y_maximum - crop_box.y_minumum) as f64,
+        )
+    }
+}
+
+/// A square of `size` centered on `center`, at least a pixel wide.
+pub fn square_around(center: Point2D, size: f64) -> BoundingBox {
+    let size = size.max(1.0);
+    let x_minumum = (center.x() - size / 2.0).round() as i32;
+    let y_minumum = (center.y() - size / 2.0).round() as i32;
+    BoundingBox {
+        x_minumum,
+        x_maximum: x_minumum + size.round() as i32,
+        y_minumum,
+        y_maximum: y_minumum + size.round() as i32,
+    }
+}
+
+/// Cut `crop_box` out of `image` and resize it to `width` by `height`. Parts of the box outside the
+/// image are black, so the crop keeps its aspect ratio near the edges.
+pub fn crop_padded(
+    image: &ImageBuffer<Rgb<u8>, Vec<u8>>,
+    crop_box: BoundingBox,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+    let mut canvas = ImageBuffer::new(
+        (crop_box.x_maximum - crop_box.x_minumum).max(1) as u32,
+        (crop_box.y_maximum - crop_box.y_minumum).max(1) as u32,
+    );
+    let x_start = crop_box.x_minumum.max(0);
+    let y_start = crop_box.y_minumum.max(0);
+    let x_end = crop_box.x_maximum.min(image.width() as i32);
+    let y_end = crop_box.y_maximum.min(image.height() as i32);
+    if x_end > x_start && y_end > y_start {
+        let inside = crop_imm(
+            image,
+            x_start as u32,
+            y_start as u32,
+            (x_end - x_start) as u32,
+            (y_end - y_start) as u32,
+        )
+        .to_image();
+        replace(
+            &mut canvas,
+            &inside,
+            (x_start - crop_box.x_minumum) as u32,
+            (y_start - crop_box.y_minumum) as u32,
+        );
+    }
+    resize(&canvas, width, height, FilterType::Triangle)
+}
+
+/// The face detector for the OpenCV backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpenCVDetector {
+    /// A Haar or LBP cascade, such as `haarcascade_frontalface_default.xml` or
+    /// `lbpcascade_frontalface_improved.xml`.
+    Cascade {
+        cascade_path: String,
+        scale_factor: f64,
+        min_neighbors: i32,
+    },
+    /// The res10 300x300 SSD from OpenCV's face detector sample.
+    Res10Ssd {
+        prototxt_path: String,
+        model_path: String,
+        score_threshold: f32,
+    },
+}
+
+impl OpenCVDetector {
+    pub fn cascade(cascade_path: String) -> Self {
+        OpenCVDetector::Cascade {
+            cascade_path,
+            scale_factor: 1.1,
+            min_neighbors: 3,
+        }
+    }
+
+    /// `deploy.prototxt` and `res10_300x300_ssd_iter_140000.caffemodel`.
+    pub fn res10_ssd(prototxt_path: String, model_path: String) -> Self {
+        OpenCVDetector::Res10Ssd {
+            prototxt_path,
+            model_path,
+            score_threshold: 0.5,
+        }
+    }
+}
+
+/// The face detector for the dlib backend.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DLibDetector {
+    /// HOG and a linear SVM. Fast on the CPU, but misses faces that are not roughly frontal.
+    Hog,
+    /// The MMOD CNN, `mmod_human_face_detector.dat`. Much more robust, but slow without a GPU.
+    Cnn { model_path: String },
+}
+
+impl Default for DLibDetector {
+    fn default() -> Self {
+        DLibDetector::Hog
+    }
+}
@@ -1,7 +1,65 @@
-use crate::utils::misc::Point2D;
+use crate::{
+    error::FacialProcessingError,
+    pt_dist,
+    utils::{face::FaceLandmark, misc::Point2D},
+};
 
 #[derive(Copy, Clone, Debug, Default, PartialOrd, PartialEq)]
 pub struct Mouth {
     pub points_outer: [Point2D; 12],
     pub points_inner: [Point2D; 8],
 }
+impl Mouth {
+    pub fn new(landmarks: &FaceLandmark) -> Result<Self, FacialProcessingError> {
+        let (points_inner, points_outer) = landmarks.mouth_landmarks()?;
+        Ok(Mouth {
+            points_outer,
+            points_inner,
+        })
+    }
+
+    /// How far the mouth is open: the mean height between the inner lips over their width, like the
+    /// eye aspect ratio. About 0 when closed.
+    pub fn mouth_aspect_ratio(&self) -> f64 {
+        let inner = &self.points_inner;
+        let vertical = pt_dist!(inner[1], inner[7])
+            + pt_dist!(inner[2], inner[6])
+            + pt_dist!(inner[3], inner[5]);
+        let mut horizontal = pt_dist!(inner[0], inner[4]);
+        if horizontal == 0_f64 {
+            horizontal = 1.0_f64;
+        }
+        vertical / (3.0 * horizontal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aspect_ratio_of_open_and_closed_mouths() {
+        let inner = |open: f64| {
+            [
+                Point2D::new(0.0, 0.0),
+                Point2D::new(1.0, -open),
+                Point2D::new(2.0, -open),
+                Point2D::new(3.0, -open),
+                Point2D::new(4.0, 0.0),
+                Point2D::new(3.0, open),
+                Point2D::new(2.0, open),
+                Point2D::new(1.0, open),
+            ]
+        };
+        let open = Mouth {
+            points_inner: inner(1.0),
+            ..Mouth::default()
+        };
+        assert!((open.mouth_aspect_ratio() - 0.5).abs() < 1e-12);
+        let closed = Mouth {
+            points_inner: inner(0.0),
+            ..Mouth::default()
+        };
+        assert_eq!(closed.mouth_aspect_ratio(), 0.0);
+    }
+}
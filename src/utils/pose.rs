@@ -0,0 +1,571 @@
+use crate::{
+    error::FacialProcessingError,
+    utils::{
+        face::FaceLandmark,
+        misc::{AngleUnit, EulerAngles, PnPSolver, RotationOrder},
+    },
+};
+use nalgebra::{Isometry3, Matrix3, Rotation3, Translation3, UnitQuaternion, Vector3};
+use opencv::{
+    core::{Mat, Scalar, CV_64F},
+    prelude::*,
+};
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+};
+
+/// How well a PnP solution explains the landmarks it was solved from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PoseFit {
+    inliers: Vec<usize>,
+    reprojection_errors: Vec<(usize, f64)>,
+    mean_reprojection_error: f64,
+    confidence: f64,
+}
+
+impl PoseFit {
+    /// `reprojection_errors` has one entry (in pixels) per landmark in `landmark_indices`, and `inliers`
+    /// are positions into both. Both are stored by landmark index. `error_scale` is the error (in pixels)
+    /// at which confidence has dropped to ~37%.
+    pub fn new(
+        landmark_indices: &[usize],
+        reprojection_errors: Vec<f64>,
+        inliers: &[usize],
+        error_scale: f64,
+    ) -> Self {
+        let inlier_errors: Vec<f64> = inliers
+            .iter()
+            .filter_map(|idx| reprojection_errors.get(*idx).copied())
+            .collect();
+        let mean_reprojection_error = if inlier_errors.is_empty() {
+            f64::INFINITY
+        } else {
+            inlier_errors.iter().sum::<f64>() / inlier_errors.len() as f64
+        };
+
+        let inlier_ratio = if reprojection_errors.is_empty() {
+            0_f64
+        } else {
+            inlier_errors.len() as f64 / reprojection_errors.len() as f64
+        };
+        let confidence =
+            inlier_ratio * (-mean_reprojection_error / error_scale.max(f64::EPSILON)).exp();
+
+        PoseFit {
+            inliers: inliers
+                .iter()
+                .filter_map(|idx| landmark_indices.get(*idx).copied())
+                .collect(),
+            reprojection_errors: landmark_indices
+                .iter()
+                .copied()
+                .zip(reprojection_errors)
+                .collect(),
+            mean_reprojection_error,
+            confidence,
+        }
+    }
+
+    /// Get a reference to the landmark indices that the solver considered inliers.
+    pub fn inliers(&self) -> &Vec<usize> {
+        &self.inliers
+    }
+
+    /// Get a reference to the `(landmark index, reprojection error in pixels)` of every correspondence used.
+    pub fn reprojection_errors(&self) -> &Vec<(usize, f64)> {
+        &self.reprojection_errors
+    }
+
+    /// Get the mean reprojection error (in pixels) of the inliers.
+    pub fn mean_reprojection_error(&self) -> f64 {
+        self.mean_reprojection_error
+    }
+
+    /// Get the confidence of the pose, from 0 to 1. This combines the inlier ratio and reprojection error,
+    /// and is meant for rejecting bad fits rather than as a probability.
+    pub fn confidence(&self) -> f64 {
+        self.confidence
+    }
+}
+
+/// The full result of a PnP solve: where the head is, and how it is turned.
+/// The translation is expressed in the units of the 3D face model used to solve it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeadPose {
+    rotation_vector: Vector3<f64>,
+    rotation_matrix: Matrix3<f64>,
+    quaternion: UnitQuaternion<f64>,
+    translation: Vector3<f64>,
+    euler_angles: EulerAngles,
+    fit: Option<PoseFit>,
+    ambiguity: Option<PoseAmbiguity>,
+}
+
+impl HeadPose {
+    /// Build a `HeadPose` from the `rvec` and `tvec` outputs of `solve_pnp`.
+    pub fn from_rvec_tvec(rvec: &Mat, tvec: &Mat) -> Result<Self, FacialProcessingError> {
+        let rotation_vector = mat_to_vector3(rvec)?;
+        let translation = mat_to_vector3(tvec)?;
+
+        let rotation = Rotation3::new(rotation_vector);
+        let euler_angles = EulerAngles::from_rotation_matrix(
+            &(rotation.matrix() * facing_camera()),
+            RotationOrder::Xyz,
+            AngleUnit::Degrees,
+        );
+        Ok(HeadPose {
+            rotation_vector,
+            rotation_matrix: *rotation.matrix(),
+            quaternion: UnitQuaternion::from_rotation_matrix(&rotation),
+            translation,
+            euler_angles,
+            fit: None,
+            ambiguity: None,
+        })
+    }
+
+    /// Attach the fit diagnostics of the solve that produced this pose.
+    pub fn with_fit(self, fit: PoseFit) -> Self {
+        HeadPose {
+            fit: Some(fit),
+            ..self
+        }
+    }
+
+    /// Record that this pose was picked over another, equally plausible one.
+    pub fn with_ambiguity(self, ambiguity: PoseAmbiguity) -> Self {
+        HeadPose {
+            ambiguity: Some(ambiguity),
+            ..self
+        }
+    }
+
+    /// Get the rotation vector (axis-angle, radians), as used by OpenCV.
+    pub fn rotation_vector(&self) -> Vector3<f64> {
+        self.rotation_vector
+    }
+
+    /// Get the 3x3 rotation matrix.
+    pub fn rotation_matrix(&self) -> Matrix3<f64> {
+        self.rotation_matrix
+    }
+
+    /// Get the rotation as a unit quaternion. Prefer this for interpolation, it does not gimbal lock.
+    pub fn quaternion(&self) -> UnitQuaternion<f64> {
+        self.quaternion
+    }
+
+    /// Get the translation of the head from the camera, in model units.
+    pub fn translation(&self) -> Vector3<f64> {
+        self.translation
+    }
+
+    /// Get the euler angles (in degrees, `Xyz` order) of the head, relative to looking straight into the camera.
+    pub fn euler_angles(&self) -> EulerAngles {
+        self.euler_angles
+    }
+
+    /// Get the euler angles of the head in another convention.
+    pub fn euler_angles_with(&self, order: RotationOrder, unit: AngleUnit) -> EulerAngles {
+        EulerAngles::from_rotation_matrix(&(self.rotation_matrix * facing_camera()), order, unit)
+    }
+
+    /// Get a reference to the fit diagnostics, if this pose came from `PnPSolver`.
+    pub fn fit(&self) -> &Option<PoseFit> {
+        &self.fit
+    }
+
+    /// Get the rejected alternative, if this pose was ambiguous. Only `PoseTracker` sets this.
+    pub fn ambiguity(&self) -> Option<PoseAmbiguity> {
+        self.ambiguity
+    }
+
+    /// Get the pose as a `nalgebra` isometry (model space to camera space).
+    pub fn isometry(&self) -> Isometry3<f64> {
+        Isometry3::from_parts(Translation3::from(self.translation), self.quaternion)
+    }
+}
+
+impl From<HeadPose> for Isometry3<f64> {
+    fn from(pose: HeadPose) -> Self {
+        pose.isometry()
+    }
+}
+
+impl Display for HeadPose {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "(rotation: {}, translation: ({}, {}, {}))",
+            self.euler_angles, self.translation.x, self.translation.y, self.translation.z
+        )
+    }
+}
+
+/// Which criterion picked a pose when two PnP solutions explained the landmarks about equally well.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AmbiguityResolution {
+    /// The chosen pose also had the lower reprojection error.
+    ReprojectionError,
+    /// The chosen pose had the higher reprojection error, but is much closer to the previous frame.
+    TemporalContinuity,
+}
+
+/// The solution that was rejected when a pose was ambiguous.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PoseAmbiguity {
+    alternative_rotation: UnitQuaternion<f64>,
+    alternative_translation: Vector3<f64>,
+    alternative_error: f64,
+    resolved_by: AmbiguityResolution,
+}
+
+impl PoseAmbiguity {
+    /// Get the rotation of the rejected solution.
+    pub fn alternative_rotation(&self) -> UnitQuaternion<f64> {
+        self.alternative_rotation
+    }
+
+    /// Get the translation of the rejected solution.
+    pub fn alternative_translation(&self) -> Vector3<f64> {
+        self.alternative_translation
+    }
+
+    /// Get the mean reprojection error (in pixels) of the rejected solution.
+    pub fn alternative_error(&self) -> f64 {
+        self.alternative_error
+    }
+
+    /// Get how the ambiguity was resolved.
+    pub fn resolved_by(&self) -> AmbiguityResolution {
+        self.resolved_by
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct TrackedPose {
+    rotation_vector: Vector3<f64>,
+    translation: Vector3<f64>,
+}
+
+/// Solves PnP for several faces over time. Each face (identified by the caller's id) is warm-started
+/// from its pose in the previous frame, which keeps EPnP from flipping the head between frames.
+/// A face is dropped (and solved from scratch next time) when solving fails or the fit confidence
+/// falls under `min_confidence`.
+///
+/// With flip suppression on (the default), IPPE's two solutions for the flattest part of the face model
+/// are refined as well, and every distinct pose is scored by reprojection error plus `temporal_weight`
+/// pixels per degree away from the previous frame's rotation. This roughly triples the cost per face;
+/// turn it off with `set_flip_suppression(false)` if the solver does not flip.
+pub struct PoseTracker {
+    solver: PnPSolver,
+    tracked: HashMap<usize, TrackedPose>,
+    min_confidence: f64,
+    flip_suppression: bool,
+    temporal_weight: f64,
+    ambiguity_margin: f64,
+}
+
+impl PoseTracker {
+    pub fn new(solver: PnPSolver, min_confidence: f64) -> Self {
+        PoseTracker {
+            solver,
+            tracked: HashMap::new(),
+            min_confidence,
+            flip_suppression: true,
+            temporal_weight: 0.1,
+            ambiguity_margin: 1.0,
+        }
+    }
+
+    pub fn forward(
+        &mut self,
+        face_id: usize,
+        data: FaceLandmark,
+    ) -> Result<HeadPose, FacialProcessingError> {
+        let previous = self.tracked.get(&face_id).copied();
+
+        let warm = match &previous {
+            Some(previous) => match self.candidates_from(Some(previous), data.clone()) {
+                Ok(candidates) => match self.choose(candidates, Some(previous)) {
+                    Ok(pose) if pose_confidence(&pose) >= self.min_confidence => Some(pose),
+                    _ => None,
+                },
+                Err(_) => None,
+            },
+            None => None,
+        };
+
+        let pose = match warm {
+            Some(pose) => pose,
+            None => match self.candidates_from(None, data) {
+                Ok(candidates) => match self.choose(candidates, previous.as_ref()) {
+                    Ok(pose) => pose,
+                    Err(why) => {
+                        self.tracked.remove(&face_id);
+                        return Err(why);
+                    }
+                },
+                Err(why) => {
+                    self.tracked.remove(&face_id);
+                    return Err(why);
+                }
+            },
+        };
+
+        if pose_confidence(&pose) >= self.min_confidence {
+            self.tracked.insert(
+                face_id,
+                TrackedPose {
+                    rotation_vector: pose.rotation_vector(),
+                    translation: pose.translation(),
+                },
+            );
+        } else {
+            self.tracked.remove(&face_id);
+        }
+        Ok(pose)
+    }
+
+    fn candidates_from(
+        &self,
+        previous: Option<&TrackedPose>,
+        data: FaceLandmark,
+    ) -> Result<Vec<HeadPose>, FacialProcessingError> {
+        let guess = match previous {
+            Some(previous) => {
+                let rvec = match vector3_to_mat(&previous.rotation_vector) {
+                    Ok(m) => m,
+                    Err(why) => return Err(why),
+                };
+                let tvec = match vector3_to_mat(&previous.translation) {
+                    Ok(m) => m,
+                    Err(why) => return Err(why),
+                };
+                Some((rvec, tvec))
+            }
+            None => None,
+        };
+        let guess_ref = guess.as_ref().map(|(rvec, tvec)| (rvec, tvec));
+
+        if self.flip_suppression {
+            self.solver.forward_candidates(data, guess_ref)
+        } else {
+            match self.solver.raw_forward_with_guess(data, guess_ref) {
+                Ok((rvec, tvec, fit)) => match HeadPose::from_rvec_tvec(&rvec, &tvec) {
+                    Ok(pose) => Ok(vec![pose.with_fit(fit)]),
+                    Err(why) => Err(why),
+                },
+                Err(why) => Err(why),
+            }
+        }
+    }
+
+    fn choose(
+        &self,
+        candidates: Vec<HeadPose>,
+        previous: Option<&TrackedPose>,
+    ) -> Result<HeadPose, FacialProcessingError> {
+        let previous_rotation =
+            previous.map(|prev| UnitQuaternion::from_scaled_axis(prev.rotation_vector));
+        let score = |pose: &HeadPose| {
+            let temporal = match &previous_rotation {
+                Some(prev) => self.temporal_weight * pose.quaternion().angle_to(prev).to_degrees(),
+                None => 0_f64,
+            };
+            pose_error(pose) + temporal
+        };
+
+        let mut candidates = candidates;
+        candidates.sort_by(|a, b| {
+            score(a)
+                .partial_cmp(&score(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut candidates = candidates.into_iter();
+        let best = match candidates.next() {
+            Some(best) => best,
+            None => {
+                return Err(FacialProcessingError::InternalError(
+                    "PnP returned no pose to choose from".to_string(),
+                ))
+            }
+        };
+
+        let alternative = candidates.min_by(|a, b| {
+            pose_error(a)
+                .partial_cmp(&pose_error(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        match alternative {
+            Some(alt) if (pose_error(&alt) - pose_error(&best)).abs() <= self.ambiguity_margin => {
+                let resolved_by = if pose_error(&best) <= pose_error(&alt) {
+                    AmbiguityResolution::ReprojectionError
+                } else {
+                    AmbiguityResolution::TemporalContinuity
+                };
+                Ok(best.with_ambiguity(PoseAmbiguity {
+                    alternative_rotation: alt.quaternion(),
+                    alternative_translation: alt.translation(),
+                    alternative_error: pose_error(&alt),
+                    resolved_by,
+                }))
+            }
+            _ => Ok(best),
+        }
+    }
+
+    /// Forget a face, e.g. when its track is lost. The next solve for it starts from scratch.
+    pub fn reset(&mut self, face_id: usize) {
+        self.tracked.remove(&face_id);
+    }
+
+    /// Forget every face.
+    pub fn reset_all(&mut self) {
+        self.tracked.clear();
+    }
+
+    /// Forget every face not in `face_ids`. Call this once per frame with the faces that are still tracked.
+    pub fn retain(&mut self, face_ids: &[usize]) {
+        self.tracked.retain(|id, _| face_ids.contains(id));
+    }
+
+    /// Whether the next solve for this face will be warm-started.
+    pub fn is_tracking(&self, face_id: usize) -> bool {
+        self.tracked.contains_key(&face_id)
+    }
+
+    /// Get a reference to the pose tracker's solver.
+    pub fn solver(&self) -> &PnPSolver {
+        &self.solver
+    }
+
+    /// Get a mutable reference to the pose tracker's solver.
+    pub fn solver_mut(&mut self) -> &mut PnPSolver {
+        &mut self.solver
+    }
+
+    /// Get the pose tracker's min confidence.
+    pub fn min_confidence(&self) -> f64 {
+        self.min_confidence
+    }
+
+    /// Set the pose tracker's min confidence.
+    pub fn set_min_confidence(&mut self, min_confidence: f64) {
+        self.min_confidence = min_confidence;
+    }
+
+    /// Get whether the pose tracker solves for and suppresses flipped poses.
+    pub fn flip_suppression(&self) -> bool {
+        self.flip_suppression
+    }
+
+    /// Set whether the pose tracker solves for and suppresses flipped poses.
+    pub fn set_flip_suppression(&mut self, flip_suppression: bool) {
+        self.flip_suppression = flip_suppression;
+    }
+
+    /// Get the pose tracker's temporal weight, in pixels of reprojection error per degree.
+    pub fn temporal_weight(&self) -> f64 {
+        self.temporal_weight
+    }
+
+    /// Set the pose tracker's temporal weight, in pixels of reprojection error per degree.
+    pub fn set_temporal_weight(&mut self, temporal_weight: f64) {
+        self.temporal_weight = temporal_weight;
+    }
+
+    /// Get the pose tracker's ambiguity margin: how close (in pixels) two solutions' errors must be to count as ambiguous.
+    pub fn ambiguity_margin(&self) -> f64 {
+        self.ambiguity_margin
+    }
+
+    /// Set the pose tracker's ambiguity margin.
+    pub fn set_ambiguity_margin(&mut self, ambiguity_margin: f64) {
+        self.ambiguity_margin = ambiguity_margin;
+    }
+}
+
+// The model faces +z with y up, the camera looks down +z with y down. This is the model to camera
+// rotation of a face looking straight into the camera, and its own inverse.
+fn facing_camera() -> Matrix3<f64> {
+    Matrix3::from_diagonal(&Vector3::new(1.0, -1.0, -1.0))
+}
+
+fn pose_confidence(pose: &HeadPose) -> f64 {
+    match pose.fit() {
+        Some(fit) => fit.confidence(),
+        None => 0_f64,
+    }
+}
+
+fn pose_error(pose: &HeadPose) -> f64 {
+    match pose.fit() {
+        Some(fit) => fit.mean_reprojection_error(),
+        None => f64::INFINITY,
+    }
+}
+
+pub(crate) fn vector3_to_mat(vector: &Vector3<f64>) -> Result<Mat, FacialProcessingError> {
+    let mut mat = match Mat::new_rows_cols_with_default(3, 1, CV_64F, Scalar::all(0.0)) {
+        Ok(m) => m,
+        Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+    };
+    for (idx, value) in vector.iter().enumerate() {
+        match mat.at_mut::<f64>(idx as i32) {
+            Ok(v) => *v = *value,
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        }
+    }
+    Ok(mat)
+}
+
+pub(crate) fn mat_to_vector3(mat: &Mat) -> Result<Vector3<f64>, FacialProcessingError> {
+    let mut values = [0_f64; 3];
+    for (idx, value) in values.iter_mut().enumerate() {
+        *value = match mat.at::<f64>(idx as i32) {
+            Ok(v) => *v,
+            Err(why) => return Err(FacialProcessingError::InternalError(why.to_string())),
+        };
+    }
+    Ok(Vector3::new(values[0], values[1], values[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pose_fit_indexes_by_landmark() {
+        let fit = PoseFit::new(&[30, 8, 36], vec![1.0, 3.0, 8.0], &[0, 1], 2.0);
+        assert_eq!(fit.inliers(), &vec![30, 8]);
+        assert_eq!(
+            fit.reprojection_errors(),
+            &vec![(30, 1.0), (8, 3.0), (36, 8.0)]
+        );
+    }
+
+    #[test]
+    fn pose_fit_mean_and_confidence() {
+        let fit = PoseFit::new(&[30, 8, 36, 45], vec![1.0, 3.0, 8.0, 2.0], &[0, 1, 3], 2.0);
+        assert!((fit.mean_reprojection_error() - 2.0).abs() < 1e-12);
+        // 3 of 4 inliers, at a mean error equal to the error scale.
+        let expected = 0.75 * (-1_f64).exp();
+        assert!((fit.confidence() - expected).abs() < 1e-12);
+
+        let perfect = PoseFit::new(&[0, 1, 2, 3], vec![0.0; 4], &[0, 1, 2, 3], 2.0);
+        assert!((perfect.confidence() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn pose_fit_without_inliers() {
+        let fit = PoseFit::new(&[0, 1, 2, 3], vec![1.0; 4], &[], 2.0);
+        assert!(fit.inliers().is_empty());
+        assert!(fit.mean_reprojection_error().is_infinite());
+        assert_eq!(fit.confidence(), 0.0);
+
+        let empty = PoseFit::new(&[], vec![], &[], 2.0);
+        assert_eq!(empty.confidence(), 0.0);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::{
+    error::FacialProcessingError,
+    mat_init,
+    utils::frame::{Frame, RgbImage},
+};
+use image::ImageBuffer;
+use opencv::{
+    prelude::*,
+    videoio::{VideoCapture, CAP_ANY, CAP_PROP_FPS, CAP_PROP_POS_MSEC},
+};
+use std::{
+    io::{self, Read, Stdin},
+    path::Path,
+    time::Duration,
+};
+
+/// A frame read from a `FrameSource`, with its position in the stream.
+#[derive(Clone, Debug)]
+pub struct TimestampedFrame {
+    /// Counts from 0 for the first frame the source returned.
+    pub index: u64,
+    /// Time since the start of the stream.
+    pub timestamp: Duration,
+    pub frame: Frame<'static>,
+}
+
+/// Something to read frames from one after another, such as a recording. Feed each frame's `frame`
+/// to the `FaceProcessor`.
+pub trait FrameSource {
+    /// `None` once the stream has ended.
+    fn next_frame(&mut self) -> Result<Option<TimestampedFrame>, FacialProcessingError>;
+
+    /// Iterate over the remaining frames, stopping after the end or the first error.
+    fn frames(&mut self) -> Frames<'_, Self>
+    where
+        Self: Sized,
+    {
+        Frames {
+            source: self,
+            done: false,
+        }
+    }
+}
+
+/// The iterator returned by `FrameSource::frames`.
+pub struct Frames<'a, S: FrameSource> {
+    source: &'a mut S,
+    done: bool,
+}
+
+impl<'a, S: FrameSource> Iterator for Frames<'a, S> {
+    type Item = Result<TimestampedFrame, FacialProcessingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.source.next_frame() {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(why) => {
+                self.done = true;
+                Some(Err(why))
+            }
+        }
+    }
+}
+
+// The time of frame `index` at a constant frame rate.
+fn frame_time(index: u64, fps: f64) -> Duration {
+    if fps > 0.0 {
+        Duration::from_secs_f64(index as f64 / fps)
+    } else {
+        Duration::default()
+    }
+}
+
+/// Frames of a video file, decoded by OpenCV's `VideoCapture`. Timestamps are the container's,
+/// so they follow variable frame rates.
+pub struct VideoFileSource {
+    capture: VideoCapture,
+    fps: f64,
+    index: u64,
+}
+
+impl VideoFileSource {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, FacialProcessingError> {
+        let path = path.as_ref().to_string_lossy().to_string();
+        let capture = match VideoCapture::from_file(&path, CAP_ANY) {
+            Ok(c) => c,
+            Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+        };
+        match capture.is_opened() {
+            Ok(true) => {}
+            Ok(false) => {
+                return Err(FacialProcessingError::IoError(format!(
+                    "Could not open video {}",
+                    path
+                )))
+            }
+            Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+        }
+        let fps = capture.get(CAP_PROP_FPS).unwrap_or(0.0);
+        Ok(VideoFileSource {
+            capture,
+            fps,
+            index: 0,
+        })
+    }
+
+    /// The frame rate the container reports, 0 if it does not.
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+}
+
+impl FrameSource for VideoFileSource {
+    fn next_frame(&mut self) -> Result<Option<TimestampedFrame>, FacialProcessingError> {
+        let mut mat = mat_init!();
+        match self.capture.read(&mut mat) {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+        }
+        // some backends report 0, negative or NaN positions, fall back to the frame rate then
+        let timestamp = match self.capture.get(CAP_PROP_POS_MSEC) {
+            Ok(msec) if msec.is_finite() && msec >= 0.0 && (msec > 0.0 || self.index == 0) => {
+                Duration::from_secs_f64(msec / 1000.0)
+            }
+            _ => frame_time(self.index, self.fps),
+        };
+        let frame = match Frame::from_mat(&mat) {
+            Ok(f) => f.into_owned(),
+            Err(why) => return Err(why),
+        };
+        let index = self.index;
+        self.index += 1;
+        Ok(Some(TimestampedFrame {
+            index,
+            timestamp,
+            frame,
+        }))
+    }
+}
+
+/// Numbered images such as `frames/%05d.png`, read until the next number is missing. `%d` and
+/// zero padded `%0Nd` are supported, as with ffmpeg and OpenCV.
+pub struct ImageSequenceSource {
+    pattern: String,
+    next_number: u64,
+    fps: f64,
+    index: u64,
+}
+
+impl ImageSequenceSource {
+    /// `fps` sets the timestamps, since images have none.
+    pub fn new(
+        pattern: String,
+        first_number: u64,
+        fps: f64,
+    ) -> Result<Self, FacialProcessingError> {
+        if let Err(why) = format_number(&pattern, first_number) {
+            return Err(why);
+        }
+        Ok(ImageSequenceSource {
+            pattern,
+            next_number: first_number,
+            fps,
+            index: 0,
+        })
+    }
+}
+
+impl FrameSource for ImageSequenceSource {
+    fn next_frame(&mut self) -> Result<Option<TimestampedFrame>, FacialProcessingError> {
+        let path = match format_number(&self.pattern, self.next_number) {
+            Ok(p) => p,
+            Err(why) => return Err(why),
+        };
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let image = match image::open(&path) {
+            Ok(i) => i,
+            Err(why) => {
+                return Err(FacialProcessingError::IoError(format!(
+                    "Could not read {}: {}",
+                    path, why
+                )))
+            }
+        };
+        let index = self.index;
+        self.index += 1;
+        self.next_number += 1;
+        Ok(Some(TimestampedFrame {
+            index,
+            timestamp: frame_time(index, self.fps),
+            frame: Frame::from(image),
+        }))
+    }
+}
+
+// Replace the one `%d` or `%0Nd` in `pattern` with `number`.
+fn format_number(pattern: &str, number: u64) -> Result<String, FacialProcessingError> {
+    let invalid = || {
+        FacialProcessingError::InvalidFrame(format!(
+            "Image sequence pattern {} needs one %d or %0Nd",
+            pattern
+        ))
+    };
+    let start = match pattern.find('%') {
+        Some(s) => s,
+        None => return Err(invalid()),
+    };
+    let rest = &pattern[start + 1..];
+    let end = match rest.find('d') {
+        Some(e) => e,
+        None => return Err(invalid()),
+    };
+    let width = match &rest[..end] {
+        "" => 0,
+        spec if spec.starts_with('0') => match spec.parse::<usize>() {
+            Ok(w) => w,
+            Err(_) => return Err(invalid()),
+        },
+        _ => return Err(invalid()),
+    };
+    Ok(format!(
+        "{}{:0width$}{}",
+        &pattern[..start],
+        number,
+        &rest[end + 1..],
+        width = width
+    ))
+}
+
+/// Raw `rgb24` frames back to back, as `ffmpeg -f rawvideo -pix_fmt rgb24 -` writes them. The size
+/// is not part of the stream, so it has to be given.
+pub struct RawRgbSource<R: Read> {
+    reader: R,
+    width: u32,
+    height: u32,
+    fps: f64,
+    index: u64,
+}
+
+impl<R: Read> RawRgbSource<R> {
+    /// `fps` sets the timestamps, since raw frames have none.
+    pub fn new(
+        reader: R,
+        width: u32,
+        height: u32,
+        fps: f64,
+    ) -> Result<Self, FacialProcessingError> {
+        if width == 0 || height == 0 {
+            return Err(FacialProcessingError::InvalidFrame(format!(
+                "Raw frames cannot be {}x{}",
+                width, height
+            )));
+        }
+        Ok(RawRgbSource {
+            reader,
+            width,
+            height,
+            fps,
+            index: 0,
+        })
+    }
+}
+
+impl RawRgbSource<Stdin> {
+    /// Frames piped on stdin.
+    pub fn stdin(width: u32, height: u32, fps: f64) -> Result<Self, FacialProcessingError> {
+        RawRgbSource::new(io::stdin(), width, height, fps)
+    }
+}
+
+impl<R: Read> FrameSource for RawRgbSource<R> {
+    fn next_frame(&mut self) -> Result<Option<TimestampedFrame>, FacialProcessingError> {
+        let mut data = vec![0_u8; self.width as usize * self.height as usize * 3];
+        // the stream may only end between frames
+        let mut filled = 0;
+        while filled < data.len() {
+            match self.reader.read(&mut data[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(FacialProcessingError::IoError(format!(
+                        "Stream ended {} bytes into frame {}",
+                        filled, self.index
+                    )))
+                }
+                Ok(read) => filled += read,
+                Err(why) if why.kind() == io::ErrorKind::Interrupted => {}
+                Err(why) => return Err(FacialProcessingError::IoError(why.to_string())),
+            }
+        }
+        let image: RgbImage = match ImageBuffer::from_raw(self.width, self.height, data) {
+            Some(i) => i,
+            None => {
+                return Err(FacialProcessingError::InvalidFrame(format!(
+                    "{}x{} RGB does not fit in memory",
+                    self.width, self.height
+                )))
+            }
+        };
+        let index = self.index;
+        self.index += 1;
+        Ok(Some(TimestampedFrame {
+            index,
+            timestamp: frame_time(index, self.fps),
+            frame: Frame::from(image),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_number_pads_and_rejects_bad_patterns() {
+        assert_eq!(format_number("frames/%d.png", 7).unwrap(), "frames/7.png");
+        assert_eq!(
+            format_number("frames/%05d.png", 42).unwrap(),
+            "frames/00042.png"
+        );
+        assert_eq!(format_number("%03d", 12345).unwrap(), "12345");
+        assert!(format_number("frames/1.png", 1).is_err());
+        assert!(format_number("frames/%s.png", 1).is_err());
+        assert!(format_number("frames/%5d.png", 1).is_err());
+    }
+
+    #[test]
+    fn raw_rgb_source_rejects_empty_frames() {
+        assert!(RawRgbSource::new(io::empty(), 0, 2, 30.0).is_err());
+        assert!(RawRgbSource::new(io::empty(), 2, 0, 30.0).is_err());
+    }
+
+    #[test]
+    fn raw_rgb_source_reads_whole_frames_until_the_end() {
+        let data: Vec<u8> = (0..24).collect();
+        let mut source = RawRgbSource::new(&data[..], 2, 2, 10.0).unwrap();
+
+        let first = source.next_frame().unwrap().unwrap();
+        assert_eq!(first.index, 0);
+        assert_eq!(first.timestamp, Duration::from_secs(0));
+        assert_eq!(first.frame.image().as_raw(), &data[..12].to_vec());
+
+        let second = source.next_frame().unwrap().unwrap();
+        assert_eq!(second.index, 1);
+        assert_eq!(second.timestamp, Duration::from_millis(100));
+        assert_eq!(second.frame.image().as_raw(), &data[12..].to_vec());
+
+        assert!(source.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn raw_rgb_source_errors_on_a_partial_frame() {
+        let data = [0_u8; 15];
+        let mut source = RawRgbSource::new(&data[..], 2, 2, 10.0).unwrap();
+        let frames: Vec<_> = source.frames().collect();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].is_ok());
+        assert!(frames[1].is_err());
+    }
+}